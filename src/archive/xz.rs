@@ -25,8 +25,11 @@ use self::tar::Header;
 
 use self::lzma::LzmaReader;
 
+use progress::Progress;
+
 // TODO from_path should be a &Path
-pub fn extract_xz( from_path : String, to_path : &Path ) {
+// Progress is reported in bytes of file contents written, as package
+pub fn extract_xz( from_path : String, to_path : &Path, package : &str, progress : &mut dyn Progress ) {
     // Read in the xz compressed file
     let f = File::open( &from_path ).unwrap( );
 
@@ -39,6 +42,15 @@ pub fn extract_xz( from_path : String, to_path : &Path ) {
     // without trusting the lzma library
     let _ = f.read_to_end( &mut xz_bytes );
 
+    // Everything is in memory already, so going through the entries twice
+    // to learn the total is cheap
+    let mut total : u64 = 0;
+    for file in Archive::new( xz_bytes.as_slice() ).entries().unwrap() {
+        total += file.unwrap().header().size().unwrap_or(0);
+    }
+    let mut done : u64 = 0;
+    progress.extract( package, done, total );
+
     // Generate a new tape archive object from the decompressed bytes
     let mut ar = Archive::new( xz_bytes.as_slice() );
 
@@ -77,8 +89,10 @@ pub fn extract_xz( from_path : String, to_path : &Path ) {
             }
 
             // Write bytes to the new path
+            done += f_bytes.len() as u64;
             write_byte_buffer( f_bytes, &target_path );
             set_perms_from_header(file.header(), &target_path);
+            progress.extract( package, done, total );
         }
     }
 }
@@ -0,0 +1,315 @@
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use solver::package_resolver::Metadata;
+use solver::version::Version;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io { path : String, cause : String },
+}
+
+impl fmt::Display for CacheError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        match *self {
+            CacheError::Io{ ref path, ref cause } => write!(f, "could not use {}: {}", path, cause),
+        }
+    }
+}
+
+/// An archive in the cache
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub sha256     : String,
+    /// The package it was downloaded for
+    pub name       : String,
+    pub version    : String,
+    /// The repository named in its metadata, if any
+    pub repository : Option<String>,
+    pub size       : u64,
+    /// When it was last downloaded or taken from the cache, in seconds since
+    /// the epoch
+    pub used       : u64,
+    /// Whether a signature by a trusted key is kept with it
+    pub signed     : bool,
+}
+
+/// What cache clean removes. Anything left as None or false is kept
+#[derive(Clone, Debug)]
+pub struct CleanPolicy {
+    /// Keep only the newest so many versions of each package
+    pub keep_versions : Option<usize>,
+    /// Remove the least recently used archives until the rest fit
+    pub max_size      : Option<u64>,
+    /// Remove archives of packages that aren't installed
+    pub uninstalled   : bool,
+}
+
+impl CleanPolicy {
+    pub fn new() -> CleanPolicy {
+        CleanPolicy { keep_versions : None, max_size : None, uninstalled : false }
+    }
+}
+
+///
+/// Package archives kept between runs, named by their SHA-256 so the same
+/// archive is never downloaded twice, whichever repository it came from:
+///
+///     <dir>/<sha256>.tar.xz     the archive
+///     <dir>/<sha256>.tar.xz.minisig
+///                               its signature, if a trusted key made one
+///     <dir>/<sha256>.toml       name, version, and when it was last used
+///     <dir>/partial/            downloads that haven't finished yet, and
+///                               records being written
+///
+/// Each archive has its own record rather than there being one index, so an
+/// interrupted run can't lose track of the whole cache.
+///
+pub struct PackageCache {
+    dir : PathBuf,
+}
+
+impl PackageCache {
+    /// Open the cache at dir, creating it if need be
+    pub fn open( dir : &Path ) -> Result<PackageCache, CacheError> {
+        create_dir_all( dir.join("partial") ).map_err(|e| io_error( dir, e ))?;
+        Ok(PackageCache { dir : dir.to_path_buf() })
+    }
+
+    pub fn path<'a>( &self, sha256 : &'a str ) -> PathBuf {
+        self.dir.join( format!("{}.tar.xz", sha256) )
+    }
+
+    fn record<'a>( &self, sha256 : &'a str ) -> PathBuf {
+        self.dir.join( format!("{}.toml", sha256) )
+    }
+
+    fn signature_path<'a>( &self, sha256 : &'a str ) -> PathBuf {
+        self.dir.join( format!("{}.tar.xz.minisig", sha256) )
+    }
+
+    /// Where a download of meta's archive goes until it's been verified
+    pub fn partial( &self, meta : &Metadata ) -> PathBuf {
+        self.dir.join("partial").join( format!("{}-{}.tar.xz.part", meta.name, meta.version) )
+    }
+
+    ///
+    /// The cached archive with this hash, if there is one. It's up to the
+    /// caller to check it's intact, and to remove it if it isn't.
+    ///
+    pub fn lookup<'a>( &self, sha256 : &'a str ) -> Option<PathBuf> {
+        let path = self.path( sha256 );
+        if path.is_file() { Some(path) } else { None }
+    }
+
+    ///
+    /// The hash of the archive last used for meta's package from meta's
+    /// repository, for metadata that has no checksum to look it up by
+    ///
+    pub fn find( &self, meta : &Metadata ) -> Option<String> {
+        self.entries().ok()?.into_iter().rev()
+            .find(|e| e.name == meta.name && e.version == meta.version && e.repository == meta.repository)
+            .map(|e| e.sha256)
+    }
+
+    ///
+    /// The signature the archive with this hash went in with, if it had
+    /// one. It's up to the caller to check it against the keys it trusts.
+    ///
+    pub fn signature<'a>( &self, sha256 : &'a str ) -> Option<String> {
+        let mut data = String::new();
+        File::open( self.signature_path( sha256 ) ).and_then(|mut f| f.read_to_string( &mut data )).ok()?;
+        Some(data)
+    }
+
+    /// Note that the archive with this hash was used for meta just now
+    pub fn touch<'a>( &self, sha256 : &'a str, meta : &Metadata ) -> Result<(), CacheError> {
+        let path = self.record( sha256 );
+        let mut entry = toml::Table::new();
+        entry.insert("name".to_string(), toml::Value::String(meta.name.clone()));
+        entry.insert("version".to_string(), toml::Value::String(meta.version.clone()));
+        match meta.repository {
+            Some(ref r) => { entry.insert("repository".to_string(), toml::Value::String(r.clone())); },
+            None => {},
+        }
+        entry.insert("used".to_string(), toml::Value::Integer(now() as i64));
+
+        self.write( &path, toml::Value::Table(entry).to_string().as_bytes() )
+    }
+
+    ///
+    /// Move a verified download with this hash into the cache, along with
+    /// the signature it came with, if any. A signature it went in with
+    /// before is kept, even if it's now used by a repository that doesn't
+    /// need one.
+    ///
+    pub fn insert<'a>( &self, from : &Path, sha256 : &'a str, meta : &Metadata, signature : Option<&'a str> ) -> Result<PathBuf, CacheError> {
+        let path = self.path( sha256 );
+        rename( from, &path ).map_err(|e| io_error( &path, e ))?;
+        match signature {
+            Some(s) => self.write( &self.signature_path( sha256 ), s.as_bytes() )?,
+            None => {},
+        }
+        self.touch( sha256, meta )?;
+        Ok(path)
+    }
+
+    /// Take the archive with this hash out of the cache
+    pub fn remove<'a>( &self, sha256 : &'a str ) -> Result<(), CacheError> {
+        for path in [self.path( sha256 ), self.signature_path( sha256 ), self.record( sha256 )].iter() {
+            if path.exists() {
+                remove_file( path ).map_err(|e| io_error( path, e ))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Everything in the cache, least recently used first. An archive whose
+    /// record can't be read is taken out, as if it had never been cached.
+    ///
+    pub fn entries( &self ) -> Result<Vec<CacheEntry>, CacheError> {
+        let mut ret : Vec<CacheEntry> = vec!();
+        for entry in read_dir( &self.dir ).map_err(|e| io_error( &self.dir, e ))? {
+            let path = entry.map_err(|e| io_error( &self.dir, e ))?.path();
+            match path.extension() {
+                Some(e) if e == "toml" => {},
+                _ => continue,
+            }
+            let sha256 = path.file_stem().unwrap().to_string_lossy().to_string();
+
+            // A record whose archive is gone is left over from a crash
+            let size = match self.path( &sha256 ).metadata() {
+                Ok(m) => m.len(),
+                Err(_) => { let _ = remove_file( &path ); continue; },
+            };
+
+            let mut data = String::new();
+            File::open( &path ).and_then(|mut f| f.read_to_string( &mut data )).map_err(|e| io_error( &path, e ))?;
+            let value = match toml::Parser::new( data.as_str() ).parse() {
+                Some(v) => v,
+                None => { self.remove( &sha256 )?; continue; },
+            };
+            let (name, version) = match (value.get("name").and_then(|n| n.as_str()), value.get("version").and_then(|v| v.as_str())) {
+                (Some(n), Some(v)) => (n.to_string(), v.to_string()),
+                _ => { self.remove( &sha256 )?; continue; },
+            };
+            ret.push(CacheEntry {
+                sha256     : sha256.clone(),
+                name       : name,
+                version    : version,
+                repository : value.get("repository").and_then(|r| r.as_str()).map(|r| r.to_string()),
+                size       : size,
+                used       : value.get("used").and_then(|u| u.as_integer()).unwrap_or(0) as u64,
+                signed     : self.signature_path( &sha256 ).is_file(),
+            });
+        }
+
+        ret.sort_by(|a, b| a.used.cmp( &b.used ).then( a.sha256.cmp( &b.sha256 ) ));
+        Ok(ret)
+    }
+
+    ///
+    /// Remove whatever policy says should go, along with any unfinished
+    /// downloads. installed is the (name, version) of every installed
+    /// package. Returns what was removed.
+    ///
+    pub fn clean( &self, policy : &CleanPolicy, installed : &[(String, String)] ) -> Result<Vec<CacheEntry>, CacheError> {
+        let partial = self.dir.join("partial");
+        for entry in read_dir( &partial ).map_err(|e| io_error( &partial, e ))? {
+            let path = entry.map_err(|e| io_error( &partial, e ))?.path();
+            remove_file( &path ).map_err(|e| io_error( &path, e ))?;
+        }
+
+        let mut keep = self.entries()?;
+        let mut removed : Vec<CacheEntry> = vec!();
+
+        if policy.uninstalled {
+            let (gone, rest) : (Vec<CacheEntry>, Vec<CacheEntry>) = keep.into_iter().partition(|e| {
+                !installed.iter().any(|&(ref n, ref v)| *n == e.name && *v == e.version)
+            });
+            removed.extend( gone );
+            keep = rest;
+        }
+
+        match policy.keep_versions {
+            Some(n) => {
+                // Count versions, newest first, per package
+                let mut by_name : HashMap<String, Vec<String>> = HashMap::new();
+                for e in keep.iter() {
+                    let versions = by_name.entry( e.name.clone() ).or_insert(vec!());
+                    if !versions.contains( &e.version ) {
+                        versions.push( e.version.clone() );
+                    }
+                }
+                // A version is among the newest n if fewer than n are newer.
+                // Ones that can't be compared with the rest are kept
+                for versions in by_name.values_mut() {
+                    let all : Vec<Version> = versions.iter().map(|v| Version::new( v )).collect();
+                    versions.retain(|v| {
+                        let v = Version::new( v );
+                        all.iter().filter(|o| o.try_cmp( &v ) == Some(1)).count() < n
+                    });
+                }
+
+                let (rest, gone) : (Vec<CacheEntry>, Vec<CacheEntry>) = keep.into_iter().partition(|e| {
+                    by_name[&e.name].contains( &e.version )
+                });
+                removed.extend( gone );
+                keep = rest;
+            },
+            None => {},
+        }
+
+        match policy.max_size {
+            Some(max) => {
+                // keep is least recently used first
+                let mut total : u64 = keep.iter().map(|e| e.size).sum();
+                while total > max && keep.len() > 0 {
+                    let e = keep.remove(0);
+                    total -= e.size;
+                    removed.push( e );
+                }
+            },
+            None => {},
+        }
+
+        for e in removed.iter() {
+            self.remove( &e.sha256 )?;
+        }
+        Ok(removed)
+    }
+
+    ///
+    /// Put data at path by way of partial/, so a crash can't leave it half
+    /// written
+    ///
+    fn write( &self, path : &Path, data : &[u8] ) -> Result<(), CacheError> {
+        let part = self.dir.join("partial").join( path.file_name().unwrap() );
+        let mut f = File::create( &part ).map_err(|e| io_error( &part, e ))?;
+        f.write_all( data ).map_err(|e| io_error( &part, e ))?;
+        rename( &part, path ).map_err(|e| io_error( path, e ))
+    }
+}
+
+fn io_error( path : &Path, e : io::Error ) -> CacheError {
+    CacheError::Io{ path : path.to_string_lossy().to_string(), cause : e.to_string() }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since( UNIX_EPOCH ).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -1,51 +1,1230 @@
 extern crate curl;
+extern crate sha2;
+extern crate toml;
 
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::hard_link;
+use std::fs::remove_file;
+use std::io;
 use std::io::prelude::*;
+use std::mem::replace;
+use std::os::unix::ffi::OsStringExt;
 use std::path::Path;
-use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::thread::JoinHandle;
+use std::thread::sleep;
+use std::thread::spawn;
+use std::time::Duration;
+use std::time::Instant;
 use self::curl::easy::Easy;
+use self::curl::easy::Easy2;
+use self::curl::easy::Handler;
+use self::curl::easy::WriteError;
+use self::curl::multi::Easy2Handle;
+use self::curl::multi::Multi;
+use self::sha2::Digest;
+use self::sha2::Sha256;
 
-// Given a name and version, collect the archive to the dir, where it can then
-// be unarchived.
-pub fn collect_package( name : String, version : String ) -> bool {
-    // Hardcoded for now
-    let pkg_name : String = format!("{}-{}.tar.xz", name, version);
-    let remote_server = "http://127.0.0.1";
-    let port : u64 = 8000;
+use collector::cache::CacheError;
+use collector::cache::PackageCache;
+use collector::repository::Repository;
+use collector::trust::SignatureError;
+use collector::trust::TrustStore;
+use progress::Progress;
+use solver::package_resolver::Metadata;
 
-    // If using a nonstandard port, we need to use a slightly different URL
-    let url;
-    if port != 80 && port != 443 {
-        url = format!("{}:{}/{}", remote_server, port, pkg_name);
-    }else{
-        url = format!("{}/{}", remote_server, pkg_name);
+/// A mirror that failed this many times in a row is considered down. It's
+/// only tried after the healthy ones, and only once per package
+const DOWN_AFTER : u32 = 3;
+
+/// One failed try at downloading from one mirror
+#[derive(Debug)]
+pub struct Attempt {
+    pub url     : String,
+    // Counting from 1, per mirror
+    pub attempt : u32,
+    pub error   : CollectError,
+}
+
+#[derive(Debug)]
+pub enum CollectError {
+    /// curl couldn't complete the transfer at all
+    Transfer { url : String, cause : String },
+    /// The server answered with something other than 2xx
+    HttpStatus { url : String, code : u32 },
+    /// The body was cut short, or ran past the length the server sent
+    LengthMismatch { url : String, expected : u64, got : u64 },
+    /// The archive isn't the download_size in the metadata
+    SizeMismatch { url : String, expected : u64, got : u64 },
+    /// The archive doesn't hash to the checksum in the metadata
+    ChecksumMismatch { url : String, expected : String, got : String },
+    /// The metadata has a checksum we can't check, e.g. md5:...
+    BadChecksum { checksum : String },
+    /// The metadata has no checksum, and the repository requires one
+    NoChecksum { package : String, repository : String },
+    /// Every mirror of repository failed, every time it was tried
+    AllMirrorsFailed { package : String, repository : String, attempts : Vec<Attempt> },
+    /// The archive was downloaded, but couldn't be saved
+    Io { path : String, cause : String },
+    /// The archive in a local repository couldn't be read
+    Read { path : String, cause : String },
+    /// The download settings in the config file are no good
+    Config { path : String, cause : String },
+    /// Another package failed first, or the collector was told to stop
+    Cancelled,
+    /// The archive couldn't be put in or taken out of the cache
+    Cache(CacheError),
+    /// The repository requires signatures, and the archive has none
+    Unsigned { url : String },
+    /// The archive's signature is malformed, or doesn't match it
+    BadSignature { url : String, cause : String },
+}
+
+impl CollectError {
+    ///
+    /// Whether trying the same mirror again could help. A missing file or a
+    /// bad checksum will be just as missing or bad the next time, so those
+    /// go straight to the next mirror.
+    ///
+    fn retryable( &self ) -> bool {
+        match *self {
+            CollectError::Transfer{ .. } | CollectError::LengthMismatch{ .. } => true,
+            // Timeouts, rate limiting and server errors may clear up. A
+            // range that can't be satisfied goes away with the .part file
+            CollectError::HttpStatus{ code, .. } => code == 408 || code == 416 || code == 429 || code >= 500,
+            _ => false,
+        }
+    }
+
+    /// Whether the .part file left behind is no good to resume from
+    fn spoils_part( &self ) -> bool {
+        match *self {
+            CollectError::SizeMismatch{ .. } | CollectError::ChecksumMismatch{ .. } => true,
+            // Whatever is in there can't be trusted
+            CollectError::Unsigned{ .. } | CollectError::BadSignature{ .. } => true,
+            CollectError::HttpStatus{ code, .. } => code == 416,
+            _ => false,
+        }
     }
+}
 
+impl fmt::Display for CollectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CollectError::Transfer{ ref url, ref cause } =>
+                write!(f, "could not download {}: {}", url, cause),
+            CollectError::HttpStatus{ ref url, code } =>
+                write!(f, "{} answered with HTTP {}", url, code),
+            CollectError::LengthMismatch{ ref url, expected, got } =>
+                write!(f, "{} sent {} bytes, but said it would send {}", url, got, expected),
+            CollectError::SizeMismatch{ ref url, expected, got } =>
+                write!(f, "{} should be {} bytes, but is {}", url, expected, got),
+            CollectError::ChecksumMismatch{ ref url, ref expected, ref got } =>
+                write!(f, "{} should have sha256 {}, but has {}", url, expected, got),
+            CollectError::BadChecksum{ ref checksum } =>
+                write!(f, "can't verify checksum {}, only sha256 is supported", checksum),
+            CollectError::NoChecksum{ ref package, ref repository } =>
+                write!(f, "{} has no checksum, and {} doesn't allow_unchecked", package, repository),
+            CollectError::AllMirrorsFailed{ ref package, ref repository, ref attempts } => {
+                write!(f, "every mirror of {} failed for {}:", repository, package)?;
+                for a in attempts.iter() {
+                    write!(f, "\n    try {}: {}", a.attempt, a.error)?;
+                }
+                Ok(())
+            },
+            CollectError::Io{ ref path, ref cause } =>
+                write!(f, "could not write {}: {}", path, cause),
+            CollectError::Read{ ref path, ref cause } =>
+                write!(f, "could not read {}: {}", path, cause),
+            CollectError::Config{ ref path, ref cause } =>
+                write!(f, "bad download settings in {}: {}", path, cause),
+            CollectError::Cancelled =>
+                write!(f, "cancelled"),
+            CollectError::Cache(ref e) =>
+                write!(f, "{}", e),
+            CollectError::Unsigned{ ref url } =>
+                write!(f, "{} is not signed, and its repository requires signatures", url),
+            CollectError::BadSignature{ ref url, ref cause } =>
+                write!(f, "refusing the signature at {}: {}", url, cause),
+        }
+    }
+}
 
-    let mut easy = Easy::new();
-    easy.url( url.as_str() ).unwrap();
+/// How hard to try each mirror before moving on to the next
+pub struct RetryPolicy {
+    /// Tries per mirror, including the first
+    pub attempts      : u32,
+    /// The wait before the second try. It doubles for every try after that
+    pub initial_delay : Duration,
+    pub max_delay     : Duration,
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            attempts      : 3,
+            initial_delay : Duration::from_millis(500),
+            max_delay     : Duration::from_secs(8),
+        }
+    }
+
+    /// The wait after the given failed try
+    fn delay( &self, attempt : u32 ) -> Duration {
+        let mut d = self.initial_delay;
+        for _ in 1..attempt {
+            d = d * 2;
+            if d >= self.max_delay {
+                return self.max_delay;
+            }
+        }
+        return d;
+    }
+}
+
+/// How many downloads may run at once
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Across every host, copies from local repositories included
+    pub total    : usize,
+    /// To any one host, so a single mirror isn't hammered
+    pub per_host : usize,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits { total : 4, per_host : 2 }
+    }
+
+    ///
+    /// Read limits from the [downloads] table of a config file, keeping the
+    /// defaults for anything that isn't there
+    ///
+    ///     [downloads]
+    ///     connections = 8
+    ///     connections_per_host = 2
+    ///
+    pub fn load( path : &Path ) -> Result<Limits, CollectError> {
+        let mut ret = Limits::new();
+        let bad = |cause : &str| CollectError::Config{ path : path.to_string_lossy().to_string(), cause : cause.to_string() };
+
+        let mut data = String::new();
+        match File::open( path ) {
+            Ok(mut f) => { f.read_to_string( &mut data ).map_err(|e| bad( &e.to_string() ))?; },
+            Err(_) => return Ok(ret),
+        }
+        let value = toml::Parser::new( data.as_str() ).parse().ok_or(bad( "bad syntax" ))?;
+
+        let limit = |key : &str| -> Result<Option<usize>, CollectError> {
+            match value.get("downloads").and_then(|d| d.lookup(key)) {
+                Some(v) => match v.as_integer() {
+                    Some(i) if i > 0 => Ok(Some(i as usize)),
+                    _ => Err(bad( &format!("{} must be a positive number", key) )),
+                },
+                None => Ok(None),
+            }
+        };
+        ret.total = limit("connections")?.unwrap_or(ret.total);
+        ret.per_host = limit("connections_per_host")?.unwrap_or(ret.per_host);
+
+        Ok(ret)
+    }
+}
+
+///
+/// Downloads package archives, several at once, failing over between the
+/// mirrors of their repository. How each mirror has been doing is remembered
+/// across packages, so a dead mirror only slows down the first few downloads.
+///
+pub struct Collector {
+    cache    : PackageCache,
+    trust    : TrustStore,
+    retry    : RetryPolicy,
+    limits   : Limits,
+    // Failures in a row, by mirror base URL
+    failures : HashMap<String, u32>,
+    cancel   : Arc<AtomicBool>,
+}
+
+/// One package being collected
+struct Job<'a> {
+    repo     : &'a Repository,
+    meta     : &'a Metadata,
+    expected : Option<String>,
+    part     : PathBuf,
+    // Mirror base URLs in the order they're tried, and where we are in it
+    mirrors  : Vec<String>,
+    mirror   : usize,
+    // Tries on the current mirror so far, and how many it gets
+    attempt  : u32,
+    tries    : u32,
+    attempts : Vec<Attempt>,
+    // Backing off until then
+    ready_at : Instant,
+    // Bytes of the archive we have, out of how many, for progress
+    received : u64,
+    total    : Option<u64>,
+    result   : Option<Result<PathBuf, CollectError>>,
+    // Whether progress has been told about the result
+    reported : bool,
+    // The archive arrived and checks out, and its signature is on the way
+    verified : Option<Download>,
+}
+
+impl<'a> Job<'a> {
+    fn url( &self ) -> String {
+        format!("{}/{}", self.mirrors[self.mirror], file_name( self.meta ))
+    }
+
+    fn package( &self ) -> String {
+        format!("{}-{}", self.meta.name, self.meta.version)
+    }
+}
+
+/// A transfer in flight, writing to the job's .part file
+struct Sink {
+    file        : File,
+    hasher      : Sha256,
+    // Bytes that were already in the file when the transfer started
+    offset      : u64,
+    received    : u64,
+    // The Content-Length of the response, i.e. what's left to receive
+    length      : Option<u64>,
+    started     : Instant,
+    // The status of the response being received, so error pages never make
+    // it into the file. file:// and the like have none, and stay at 0
+    status      : u32,
+    write_error : Option<io::Error>,
+}
+
+impl Sink {
+    /// Bytes per second over this transfer
+    fn speed( &self ) -> u64 {
+        per_second( self.received, self.started )
+    }
+}
+
+impl Handler for Sink {
+    fn header( &mut self, data : &[u8] ) -> bool {
+        // Every response, e.g. after a redirect, starts with a status line
+        match parse_status( data ) {
+            Some(code) => { self.status = code; self.length = None; },
+            None => {},
+        }
+        match parse_length( data ) {
+            Some(n) if self.status / 100 == 2 => self.length = Some(n),
+            _ => {},
+        }
+        true
+    }
+
+    fn write( &mut self, data : &[u8] ) -> Result<usize, WriteError> {
+        if self.status != 0 && (self.status < 200 || self.status >= 300) {
+            return Ok(data.len());
+        }
+
+        // Returning short makes curl abort the transfer
+        match self.file.write_all( data ) {
+            Ok(_) => {},
+            Err(e) => { self.write_error = Some(e); return Ok(0); },
+        }
+        self.hasher.update( data );
+        self.received += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+/// A signature being fetched for an archive that has arrived
+struct SignatureSink {
+    body : Vec<u8>,
+}
+
+impl Handler for SignatureSink {
+    fn write( &mut self, data : &[u8] ) -> Result<usize, WriteError> {
+        self.body.extend_from_slice( data );
+        Ok(data.len())
+    }
+}
+
+impl Collector {
+    pub fn new( cache : PackageCache ) -> Collector {
+        Collector::with_retry( cache, RetryPolicy::new() )
+    }
+
+    pub fn with_retry( cache : PackageCache, retry : RetryPolicy ) -> Collector {
+        Collector {
+            cache    : cache,
+            trust    : TrustStore::new(),
+            retry    : retry,
+            limits   : Limits::new(),
+            failures : HashMap::new(),
+            cancel   : Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn limit( &mut self, limits : Limits ) {
+        self.limits = limits;
+    }
+
+    /// The keys archive signatures are checked against. There are none to
+    /// begin with, so only repositories that allow unsigned packages work
+    pub fn trust( &mut self, trust : TrustStore ) {
+        self.trust = trust;
+    }
+
+    ///
+    /// A flag that stops collect_all from another thread, e.g. on ^C. The
+    /// transfers in flight are dropped, and their .part files are left to
+    /// resume from next time.
+    ///
+    pub fn canceller( &self ) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
+
+    ///
+    /// Given repositories and packages, collect their archives to the cache,
+    /// returning where each can then be unarchived from. Archives already in
+    /// the cache are used without going to the network. Nothing is written
+    /// unless it matches the size and checksum in the metadata.
+    ///
+    /// Downloads run side by side within the limits. The first package that
+    /// can't be collected cancels the rest, which come back as Cancelled.
+    /// The results are in the same order as plan. How it's going is reported
+    /// to progress along the way.
+    ///
+    pub fn collect_all( &mut self, plan : &[(&Repository, &Metadata)], progress : &mut dyn Progress ) -> Vec<Result<PathBuf, CollectError>> {
+        self.cancel.store( false, Ordering::SeqCst );
+
+        let mut jobs : Vec<Job> = plan.iter().map(|&(repo, meta)| {
+            let mirrors = self.order( repo );
+            let tries = self.tries( &mirrors[0] );
+            Job {
+                repo     : repo,
+                meta     : meta,
+                expected : None,
+                // Archives are streamed into a .part file, and only go in the
+                // cache once they've been verified
+                part     : self.cache.partial( meta ),
+                mirrors  : mirrors,
+                mirror   : 0,
+                attempt  : 0,
+                tries    : tries,
+                attempts : vec!(),
+                ready_at : Instant::now(),
+                received : 0,
+                total    : meta.download_size,
+                result   : None,
+                reported : false,
+                verified : None,
+            }
+        }).collect();
+        progress.begin( &jobs.iter().map(|j| (j.package(), j.total)).collect::<Vec<_>>() );
+
+        let mut failed = false;
+        for job in jobs.iter_mut() {
+            // A checksum that's missing or we can't understand is the
+            // repository's fault, and no mirror can fix that
+            match job.meta.checksum {
+                Some(ref c) => match parse_checksum( c ) {
+                    Ok(e) => job.expected = Some(e),
+                    Err(e) => { job.result = Some(Err(e)); failed = true; },
+                },
+                None if job.repo.allow_unchecked => {},
+                None => {
+                    job.result = Some(Err(CollectError::NoChecksum{ package : job.package(), repository : job.repo.name.clone() }));
+                    failed = true;
+                },
+            }
+        }
+        for job in jobs.iter_mut().filter(|j| j.result.is_none()) {
+            match self.cached( job ) {
+                Some((path, size, sha256)) => {
+                    job.received = size;
+                    job.total = Some(size);
+                    job.result = Some(self.cache.touch( &sha256, job.meta )
+                                          .map(|_| path).map_err(CollectError::Cache));
+                    failed = failed || job.result.as_ref().unwrap().is_err();
+                },
+                None => {},
+            }
+        }
+
+        let multi = Multi::new();
+        let mut running : HashMap<usize, Easy2Handle<Sink>> = HashMap::new();
+        // Signatures go through multi alongside the archives, so fetching
+        // one doesn't hold up the other downloads. Their tokens come after
+        // the archives'
+        let mut signing : HashMap<usize, Easy2Handle<SignatureSink>> = HashMap::new();
+        let mut hosts : HashMap<String, usize> = HashMap::new();
+        // Local repositories are copied from on threads of their own, which
+        // count how far they've got, and send what they came to over copied
+        let mut copying : HashMap<usize, (Arc<AtomicU64>, Instant)> = HashMap::new();
+        let mut workers : Vec<JoinHandle<()>> = vec!();
+        let (copy_done, copied) = channel();
+        let mut copies : Vec<(usize, Result<Download, CollectError>)> = vec!();
+        let stop = Arc::new(AtomicBool::new(false));
+        // For the overall speed: bytes received by transfers that are over,
+        // since we started
+        let begun = Instant::now();
+        let mut transferred : u64 = 0;
+        let mut reported : Option<Instant> = None;
+        report( &mut jobs, progress );
+
+        while !failed && !self.cancel.load( Ordering::SeqCst ) {
+            // Start whatever the limits allow, in plan order
+            let now = Instant::now();
+            for i in 0..jobs.len() {
+                if failed || running.len() + signing.len() + copying.len() >= self.limits.total {
+                    break;
+                }
+                if jobs[i].result.is_some() || running.contains_key( &i ) || signing.contains_key( &i ) || copying.contains_key( &i ) || jobs[i].ready_at > now {
+                    continue;
+                }
+
+                match local_path( &jobs[i].url() ) {
+                    Some(path) => {
+                        // The copy replaces whatever another mirror sent
+                        jobs[i].verified = None;
+                        jobs[i].received = 0;
+                        let count = Arc::new(AtomicU64::new(0));
+                        let (part, done, count_c, stop_c) = (jobs[i].part.clone(), copy_done.clone(), count.clone(), stop.clone());
+                        workers.push( spawn(move || {
+                            let _ = done.send((i, copy_local( &path, &part, &count_c, &stop_c )));
+                        }));
+                        copying.insert( i, (count, Instant::now()) );
+                        continue;
+                    },
+                    None => {},
+                }
+
+                let host = host_of( &jobs[i].url() );
+                if *hosts.get( &host ).unwrap_or(&0) >= self.limits.per_host {
+                    continue;
+                }
+
+                // The archive checks out, so all that's left is its signature
+                if jobs[i].verified.is_some() {
+                    match start_signature( &multi, &jobs[i], jobs.len() + i ) {
+                        Ok(handle) => {
+                            signing.insert( i, handle );
+                            *hosts.entry( host ).or_insert(0) += 1;
+                        },
+                        Err(e) => {
+                            if !self.signed( &mut jobs[i], Err(e), progress ) {
+                                failed = true;
+                            }
+                        },
+                    }
+                    continue;
+                }
+
+                match start( &multi, &jobs[i], i ) {
+                    Ok(handle) => {
+                        jobs[i].received = handle.get_ref().offset;
+                        running.insert( i, handle );
+                        *hosts.entry( host ).or_insert(0) += 1;
+                    },
+                    Err(e) => { jobs[i].result = Some(Err(e)); failed = true; },
+                }
+            }
+            report( &mut jobs, progress );
+            if failed || (running.len() == 0 && jobs.iter().all(|j| j.result.is_some())) {
+                break;
+            }
+
+            match multi.perform() {
+                Ok(_) => {},
+                Err(e) => {
+                    for i in running.keys().chain( signing.keys() ) {
+                        jobs[*i].result = Some(Err(CollectError::Transfer{ url : jobs[*i].url(), cause : e.to_string() }));
+                    }
+                    break;
+                }
+            }
+
+            // Ten times a second is plenty for anyone watching
+            if reported.map(|t| t.elapsed() >= Duration::from_millis(100)).unwrap_or(true) {
+                reported = Some(Instant::now());
+                let mut current = transferred;
+                for (i, handle) in running.iter() {
+                    let sink = handle.get_ref();
+                    let job = &mut jobs[*i];
+                    job.received = sink.offset + sink.received;
+                    match sink.length {
+                        Some(l) => job.total = Some(sink.offset + l),
+                        None => {},
+                    }
+                    current += sink.received;
+                    progress.package( &job.package(), job.received, job.total, sink.speed() );
+                }
+                for (i, &(ref count, started)) in copying.iter() {
+                    let job = &mut jobs[*i];
+                    job.received = count.load( Ordering::SeqCst );
+                    current += job.received;
+                    progress.package( &job.package(), job.received, job.total, per_second( job.received, started ) );
+                }
+                progress.overall( jobs.iter().map(|j| j.received).sum(), overall_total( &jobs ), per_second( current, begun ) );
+            }
+
+            let mut done : Vec<(usize, Result<(), curl::Error>)> = vec!();
+            // Whether an archive came in whose signature should be started
+            // without waiting
+            let mut verified = false;
+            multi.messages(|m| {
+                match (m.token(), m.result()) {
+                    (Ok(i), Some(r)) => done.push((i, r)),
+                    _ => {},
+                }
+            });
+
+            for (token, result) in done {
+                if token >= jobs.len() {
+                    let i = token - jobs.len();
+                    let handle = signing.remove( &i ).unwrap();
+                    *hosts.get_mut( &host_of( &jobs[i].url() ) ).unwrap() -= 1;
+                    let outcome = multi.remove2( handle )
+                        .map_err(|e| CollectError::Transfer{ url : format!("{}.minisig", jobs[i].url()), cause : e.to_string() })
+                        .and_then(|easy| signature_response( &format!("{}.minisig", jobs[i].url()), easy, result ));
+                    if !self.signed( &mut jobs[i], outcome, progress ) {
+                        failed = true;
+                    }
+                    continue;
+                }
+
+                let i = token;
+                let handle = running.remove( &i ).unwrap();
+                *hosts.get_mut( &host_of( &jobs[i].url() ) ).unwrap() -= 1;
+                let easy = match multi.remove2( handle ) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        jobs[i].result = Some(Err(CollectError::Transfer{ url : jobs[i].url(), cause : e.to_string() }));
+                        failed = true;
+                        continue;
+                    }
+                };
+                transferred += easy.get_ref().received;
+                if !self.finish( &mut jobs[i], easy, result, progress ) {
+                    failed = true;
+                }
+                verified = verified || jobs[i].verified.is_some();
+            }
+
+            while let Ok(c) = copied.try_recv() {
+                copies.push( c );
+            }
+            for (i, outcome) in copies.drain(..) {
+                copying.remove( &i );
+                let url = jobs[i].url();
+                let outcome = outcome
+                    .and_then(|d| verify( &url, jobs[i].meta, &jobs[i].expected, &d ).map(|_| d))
+                    .and_then(|mut d| { d.signature = check_signature( &self.trust, jobs[i].repo, &jobs[i].part, &url )?; Ok(d) });
+                jobs[i].received = outcome.as_ref().map(|d| d.size).unwrap_or(0);
+                transferred += jobs[i].received;
+                if !self.settle( &mut jobs[i], url, outcome, progress ) {
+                    failed = true;
+                }
+            }
+            report( &mut jobs, progress );
+            if verified {
+                continue;
+            }
+
+            // Sleep until there's something to do, but not past the end of
+            // a backoff
+            let mut timeout = Duration::from_millis(100);
+            let now = Instant::now();
+            for j in jobs.iter().filter(|j| j.result.is_none() && j.ready_at > now) {
+                if j.ready_at - now < timeout {
+                    timeout = j.ready_at - now;
+                }
+            }
+            if running.len() > 0 || signing.len() > 0 {
+                let _ = multi.wait( &mut [], timeout );
+            } else if copying.len() > 0 {
+                match copied.recv_timeout( timeout ) {
+                    Ok(c) => copies.push( c ),
+                    Err(_) => {},
+                }
+            } else {
+                sleep( timeout );
+            }
+        }
+
+        // Dropping the handles closes the .part files as they are, to be
+        // resumed from next time
+        for (_, handle) in running.drain() {
+            let _ = multi.remove2( handle );
+        }
+        for (_, handle) in signing.drain() {
+            let _ = multi.remove2( handle );
+        }
+        // Copies still going are dropped the same way, and must be over
+        // before anyone else touches their .part files
+        stop.store( true, Ordering::SeqCst );
+        for worker in workers.drain(..) {
+            let _ = worker.join();
+        }
+
+        for job in jobs.iter_mut().filter(|j| j.result.is_none()) {
+            job.result = Some(Err(CollectError::Cancelled));
+        }
+        report( &mut jobs, progress );
+        progress.overall( jobs.iter().map(|j| j.received).sum(), overall_total( &jobs ), per_second( transferred, begun ) );
+        progress.end();
+
+        jobs.into_iter().map(|j| j.result.unwrap()).collect()
+    }
+
+    ///
+    /// The cached archive for job, its size and its hash, if there's one
+    /// that still matches the metadata. One that doesn't is thrown out, to
+    /// be downloaded again. Without a checksum in the metadata, the archive
+    /// last cached for the same package from the same repository is used.
+    ///
+    fn cached( &self, job : &Job ) -> Option<(PathBuf, u64, String)> {
+        let expected = match job.expected {
+            Some(ref e) => e.clone(),
+            None => self.cache.find( job.meta )?,
+        };
+        let path = self.cache.lookup( &expected )?;
+
+        // The metadata's checksum alone doesn't vouch for an archive, so it
+        // needs a signature the keys we trust now still accept. One that
+        // doesn't have one has to be fetched again, signature and all
+        if !job.repo.allow_unsigned {
+            let signature = self.cache.signature( &expected )?;
+            self.trust.verify( &path, &signature ).ok()?;
+        }
+
+        // Either way the archive has to still hash to what it went in as
+        let mut hasher = Sha256::new();
+        let size = File::open( &path ).and_then(|mut f| hash_into( &mut f, &mut hasher )).ok()?;
+        let download = Download { size : size, sha256 : hex_digest( hasher ), signature : None };
+        match verify( &path.to_string_lossy(), job.meta, &Some(expected.clone()), &download ) {
+            Ok(_) => Some((path, size, expected)),
+            Err(_) => { let _ = self.cache.remove( &expected ); None },
+        }
+    }
+
+    ///
+    /// Deal with a transfer that's over. An archive that checks out is left
+    /// in job.verified, for its signature to be fetched. Returns false if
+    /// the job has now failed for good.
+    ///
+    fn finish( &mut self, job : &mut Job, mut easy : Easy2<Sink>, result : Result<(), curl::Error>, progress : &mut dyn Progress ) -> bool {
+        let url = job.url();
+        job.received = easy.get_ref().offset + easy.get_ref().received;
+        let io_error = |path : &Path, e : io::Error| CollectError::Io{ path : path.to_string_lossy().to_string(), cause : e.to_string() };
+
+        // Local trouble has nothing to do with the mirror
+        match easy.get_mut().write_error.take() {
+            Some(e) => { job.result = Some(Err(io_error( &job.part, e ))); return false; },
+            None => {},
+        }
+
+        let outcome = match result {
+            // The server doesn't do ranges, so start over from nothing. That
+            // doesn't count as a try
+            Err(ref e) if e.is_range_error() && easy.get_ref().offset > 0 => {
+                drop( easy );
+                return match remove_file( &job.part ) {
+                    Ok(_) => true,
+                    Err(e) => { job.result = Some(Err(io_error( &job.part, e ))); false },
+                };
+            },
+            Err(e) => Err(CollectError::Transfer{ url : url.clone(), cause : e.to_string() }),
+            Ok(_) => check( &url, &mut easy ).and_then(|d| verify( &url, job.meta, &job.expected, &d ).map(|_| d)),
+        };
+        drop( easy );
+
+        match outcome {
+            Ok(d) => { job.verified = Some(d); true },
+            Err(e) => self.settle( job, url, Err(e), progress ),
+        }
+    }
+
+    ///
+    /// Deal with the signature fetched for job.verified, or the failure to
+    /// fetch it. Returns false if the job has now failed for good.
+    ///
+    /// Failing to fetch the signature says nothing about the archive, so
+    /// it's kept and only the signature is tried again, on this mirror or
+    /// the next.
+    ///
+    fn signed( &mut self, job : &mut Job, signature : Result<Option<String>, CollectError>, progress : &mut dyn Progress ) -> bool {
+        let url = job.url();
+        let mut download = job.verified.take().unwrap();
+        let signature = match signature {
+            Ok(s) => s,
+            Err(e) => {
+                if !e.spoils_part() {
+                    job.verified = Some(download);
+                }
+                return self.settle( job, format!("{}.minisig", url), Err(e), progress );
+            },
+        };
+        let outcome = judge_signature( &self.trust, job.repo, &job.part, &url, signature )
+            .map(|signature| { download.signature = signature; download });
+
+        self.settle( job, url, outcome, progress )
+    }
+
+    ///
+    /// Put a verified download in the cache, or decide what to do about a
+    /// failed one. Returns false if the job has now failed for good.
+    ///
+    fn settle( &mut self, job : &mut Job, url : String, outcome : Result<Download, CollectError>, progress : &mut dyn Progress ) -> bool {
+        match outcome {
+            Ok(d) => {
+                self.failures.remove( &job.mirrors[job.mirror] );
+                job.total = Some(job.received);
+                job.result = Some(self.cache.insert( &job.part, &d.sha256, job.meta, d.signature.as_ref().map(|s| s.as_str()) ).map_err(CollectError::Cache));
+                return job.result.as_ref().unwrap().is_ok();
+            },
+            Err(e) => {
+                // Resuming from bad data would only make it worse, and
+                // there's nothing to resume from an empty file
+                let empty = job.part.metadata().map(|m| m.len() == 0).unwrap_or(false);
+                if e.spoils_part() || empty {
+                    let _ = remove_file( &job.part );
+                }
+                *self.failures.entry( job.mirrors[job.mirror].clone() ).or_insert(0) += 1;
+
+                job.attempt += 1;
+                let retry = e.retryable() && job.attempt < job.tries;
+                job.attempts.push(Attempt{ url : url, attempt : job.attempt, error : e });
+
+                if retry {
+                    let delay = self.retry.delay( job.attempt );
+                    job.ready_at = Instant::now() + delay;
+                    progress.retry( &job.package(), job.attempts.last().unwrap(), delay );
+                } else if job.mirror + 1 < job.mirrors.len() {
+                    // No point waiting before moving to the next mirror
+                    job.mirror += 1;
+                    job.attempt = 0;
+                    job.tries = self.tries( &job.mirrors[job.mirror] );
+                    job.ready_at = Instant::now();
+                    progress.retry( &job.package(), job.attempts.last().unwrap(), Duration::from_secs(0) );
+                } else {
+                    job.result = Some(Err(CollectError::AllMirrorsFailed{
+                        package : format!("{}-{}", job.meta.name, job.meta.version),
+                        repository : job.repo.name.clone(),
+                        attempts : job.attempts.drain(..).collect(),
+                    }));
+                    return false;
+                }
+                return true;
+            },
+        }
+    }
+
+    fn is_down<'a>( &self, mirror : &'a str ) -> bool {
+        self.failures.get( mirror ).map(|f| *f >= DOWN_AFTER).unwrap_or(false)
+    }
+
+    /// How many tries a mirror gets
+    fn tries<'a>( &self, mirror : &'a str ) -> u32 {
+        if self.is_down( mirror ) { 1 } else { self.retry.attempts }
+    }
+
+    /// The mirrors of repo in configured order, with the ones that are down
+    /// moved to the back
+    fn order( &self, repo : &Repository ) -> Vec<String> {
+        let mut ret : Vec<String> = repo.mirrors.iter().filter(|m| !self.is_down( m )).cloned().collect();
+        ret.extend( repo.mirrors.iter().filter(|m| self.is_down( m )).cloned() );
+        return ret;
+    }
+}
+
+/// What a finished download came to
+struct Download {
+    size   : u64,
+    sha256 : String,
+    // The signature it came with, if a trusted key made it
+    signature : Option<String>,
+}
+
+/// Tell progress about the jobs that have finished since last time
+fn report( jobs : &mut [Job], progress : &mut dyn Progress ) {
+    for job in jobs.iter_mut().filter(|j| j.result.is_some() && !j.reported) {
+        job.reported = true;
+        if job.result.as_ref().unwrap().is_ok() {
+            progress.package( &job.package(), job.received, job.total, 0 );
+        }
+        progress.finished( &job.package(), job.result.as_ref().unwrap() );
+    }
+}
+
+/// The size of the whole plan, if every package's is known
+fn overall_total( jobs : &[Job] ) -> Option<u64> {
+    jobs.iter().map(|j| j.total).sum()
+}
+
+fn per_second( bytes : u64, since : Instant ) -> u64 {
+    let elapsed = since.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    if secs > 0.0 { (bytes as f64 / secs) as u64 } else { 0 }
+}
+
+fn file_name( meta : &Metadata ) -> String {
+    format!("{}-{}.tar.xz", meta.name, meta.version)
+}
+
+/// The host, and port if any, of a URL
+fn host_of<'a>( url : &'a str ) -> String {
+    let rest = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+    rest.split('/').next().unwrap_or("").to_string()
+}
+
+///
+/// Add a transfer for the job's current mirror to multi. If the .part file
+/// already holds the start of the archive from an earlier try, only the rest
+/// is asked for, with an HTTP Range request.
+///
+fn start( multi : &Multi, job : &Job, token : usize ) -> Result<Easy2Handle<Sink>, CollectError> {
+    let url = job.url();
+    let transfer_error = |e : curl::Error| CollectError::Transfer{ url : url.clone(), cause : e.to_string() };
+    let io_error = |e : io::Error| CollectError::Io{ path : job.part.to_string_lossy().to_string(), cause : e.to_string() };
+
+    // The hash has to cover what we already have, too
+    let mut hasher = Sha256::new();
+    let offset : u64 = match File::open( &job.part ) {
+        Ok(mut f) => hash_into( &mut f, &mut hasher ).map_err(&io_error)?,
+        Err(_) => 0,
+    };
+    let file = OpenOptions::new().create(true).append(true).open( &job.part ).map_err(&io_error)?;
+
+    let mut easy = Easy2::new(Sink {
+        file        : file,
+        hasher      : hasher,
+        offset      : offset,
+        received    : 0,
+        length      : None,
+        started     : Instant::now(),
+        status      : 0,
+        write_error : None,
+    });
+    easy.url( &url ).map_err(&transfer_error)?;
+    // Give up on mirrors that hang rather than waiting forever
+    easy.connect_timeout( Duration::from_secs(30) ).map_err(&transfer_error)?;
+    easy.low_speed_limit( 1 ).map_err(&transfer_error)?;
+    easy.low_speed_time( Duration::from_secs(30) ).map_err(&transfer_error)?;
+    if offset > 0 {
+        easy.resume_from( offset ).map_err(&transfer_error)?;
+    }
+
+    let mut handle = multi.add2( easy ).map_err(|e| CollectError::Transfer{ url : url.clone(), cause : e.to_string() })?;
+    handle.set_token( token ).map_err(&transfer_error)?;
+    Ok(handle)
+}
+
+///
+/// Add a transfer for the signature of the job's archive, <url>.minisig, to
+/// multi. It's small enough to be held in memory.
+///
+fn start_signature( multi : &Multi, job : &Job, token : usize ) -> Result<Easy2Handle<SignatureSink>, CollectError> {
+    let url = format!("{}.minisig", job.url());
+    let transfer_error = |e : curl::Error| CollectError::Transfer{ url : url.clone(), cause : e.to_string() };
+
+    let mut easy = Easy2::new(SignatureSink { body : vec!() });
+    easy.url( &url ).map_err(&transfer_error)?;
+    easy.connect_timeout( Duration::from_secs(30) ).map_err(&transfer_error)?;
+    easy.low_speed_limit( 1 ).map_err(&transfer_error)?;
+    easy.low_speed_time( Duration::from_secs(30) ).map_err(&transfer_error)?;
+
+    let mut handle = multi.add2( easy ).map_err(|e| CollectError::Transfer{ url : url.clone(), cause : e.to_string() })?;
+    handle.set_token( token ).map_err(&transfer_error)?;
+    Ok(handle)
+}
+
+/// What a finished signature transfer came to. None if the server doesn't
+/// have one
+fn signature_response<'a>( url : &'a str, mut easy : Easy2<SignatureSink>, result : Result<(), curl::Error> ) -> Result<Option<String>, CollectError> {
+    let transfer_error = |e : curl::Error| CollectError::Transfer{ url : url.to_string(), cause : e.to_string() };
+
+    result.map_err(&transfer_error)?;
+    let code = easy.response_code().map_err(&transfer_error)?;
+    signature_body( url, code, &easy.get_mut().body )
+}
+
+///
+/// The path a file:// URL points to, or None for any other URL. Escapes
+/// like %20 are decoded
+///
+pub fn local_path<'a>( url : &'a str ) -> Option<PathBuf> {
+    if !url.starts_with("file://") {
+        return None;
+    }
+    let rest = &url["file://".len()..];
+    let rest = if rest.starts_with("localhost/") { &rest["localhost".len()..] } else { rest };
+    Some(PathBuf::from( OsString::from_vec( percent_decode( rest ) ) ))
+}
+
+/// s with every %XX replaced by the byte it stands for. A % that isn't
+/// followed by two hex digits is left alone
+fn percent_decode<'a>( s : &'a str ) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let hex = |b : u8| (b as char).to_digit(16);
+    let mut ret : Vec<u8> = vec!();
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1).and_then(|&b| hex( b )), bytes.get(i + 2).and_then(|&b| hex( b ))) {
+            (b'%', Some(h), Some(l)) => { ret.push( (h * 16 + l) as u8 ); i += 3; },
+            (b, _, _) => { ret.push( b ); i += 1; },
+        }
+    }
+    ret
+}
+
+///
+/// Put the archive at from in part, as a hard link where the filesystem
+/// allows it and as a copy otherwise, and hash it like a download. Bytes
+/// are counted into copied as they go, and it gives up as Cancelled once
+/// stop is set.
+///
+fn copy_local( from : &Path, part : &Path, copied : &AtomicU64, stop : &AtomicBool ) -> Result<Download, CollectError> {
+    let io_error = |path : &Path, e : io::Error| CollectError::Io{ path : path.to_string_lossy().to_string(), cause : e.to_string() };
+    let read_error = |e : io::Error| CollectError::Read{ path : from.to_string_lossy().to_string(), cause : e.to_string() };
+
+    // Whatever was there is from some other mirror, and of no use
+    if part.exists() {
+        remove_file( part ).map_err(|e| io_error( part, e ))?;
+    }
+    // A link only needs hashing. Reading it back is no different from
+    // reading the original
+    let (mut input, mut output) = match hard_link( from, part ) {
+        Ok(_) => (File::open( part ).map_err(&read_error)?, None),
+        Err(_) => (File::open( from ).map_err(&read_error)?, Some(File::create( part ).map_err(|e| io_error( part, e ))?)),
+    };
 
-    let mut dst  = Vec::new();
-    // Scoping is necessry to drop the mutable reference to dst in the
-    // callback
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    let mut size : u64 = 0;
+    loop {
+        if stop.load( Ordering::SeqCst ) {
+            return Err(CollectError::Cancelled);
+        }
+        let n = input.read( &mut buf ).map_err(&read_error)?;
+        if n == 0 {
+            break;
+        }
+        match output {
+            Some(ref mut f) => f.write_all( &buf[..n] ).map_err(|e| io_error( part, e ))?,
+            None => {},
+        }
+        hasher.update( &buf[..n] );
+        size += n as u64;
+        copied.store( size, Ordering::SeqCst );
+    }
+    Ok(Download { size : size, sha256 : hex_digest( hasher ), signature : None })
+}
+
+///
+/// Check the signature published next to url, as <url>.minisig, against the
+/// file at path, which was fetched from url. Returns the signature if it
+/// was made by a trusted key.
+///
+/// Repositories that allow unsigned content accept it without a signature,
+/// or with one made by a key we don't know. A signature by a trusted key
+/// that doesn't match is refused whatever the repository allows, since
+/// nothing honest produces one.
+///
+pub fn check_signature<'a>( trust : &TrustStore, repo : &Repository, path : &Path, url : &'a str ) -> Result<Option<String>, CollectError> {
+    let sig_url = format!("{}.minisig", url);
+    let signature = match local_path( &sig_url ) {
+        Some(sig_path) => match File::open( &sig_path ) {
+            Ok(mut f) => {
+                let mut data = String::new();
+                f.read_to_string( &mut data ).map_err(|e| CollectError::Read{ path : sig_path.to_string_lossy().to_string(), cause : e.to_string() })?;
+                Some(data)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(CollectError::Read{ path : sig_path.to_string_lossy().to_string(), cause : e.to_string() }),
+        },
+        None => fetch_signature( &sig_url )?,
+    };
+
+    judge_signature( trust, repo, path, url, signature )
+}
+
+///
+/// Decide on the signature published for url, if there was one, as
+/// check_signature does.
+///
+fn judge_signature<'a>( trust : &TrustStore, repo : &Repository, path : &Path, url : &'a str, signature : Option<String> ) -> Result<Option<String>, CollectError> {
+    let sig_url = format!("{}.minisig", url);
+    let bad = |cause : String| CollectError::BadSignature{ url : sig_url.clone(), cause : cause };
+    match signature {
+        Some(s) => match trust.verify( path, &s ) {
+            Ok(_) => Ok(Some(s)),
+            Err(SignatureError::UnknownKey) if repo.allow_unsigned => Ok(None),
+            Err(e) => Err(bad( e.to_string() )),
+        },
+        None if repo.allow_unsigned => Ok(None),
+        None => Err(CollectError::Unsigned{ url : url.to_string() }),
+    }
+}
+
+///
+/// Download a signature, which is small enough to just wait for. None if
+/// the server doesn't have one. Only for use outside of collect_all, which
+/// fetches them alongside everything else.
+///
+fn fetch_signature<'a>( url : &'a str ) -> Result<Option<String>, CollectError> {
+    let transfer_error = |e : curl::Error| CollectError::Transfer{ url : url.to_string(), cause : e.to_string() };
+
+    let mut body : Vec<u8> = vec!();
+    let mut easy = Easy::new();
+    easy.url( url ).map_err(&transfer_error)?;
+    easy.connect_timeout( Duration::from_secs(30) ).map_err(&transfer_error)?;
+    easy.low_speed_limit( 1 ).map_err(&transfer_error)?;
+    easy.low_speed_time( Duration::from_secs(30) ).map_err(&transfer_error)?;
     {
         let mut transfer = easy.transfer();
-        transfer.write_function(|data| {
-            dst.extend_from_slice(data);
-            Ok(data.len())
-        }).unwrap();
-        transfer.perform().unwrap();
+        transfer.write_function(|data| { body.extend_from_slice( data ); Ok(data.len()) }).map_err(&transfer_error)?;
+        transfer.perform().map_err(&transfer_error)?;
+    }
+
+    let code = easy.response_code().map_err(&transfer_error)?;
+    signature_body( url, code, &body )
+}
+
+/// The signature in a response with the given status, if it has one
+fn signature_body<'a>( url : &'a str, code : u32, body : &[u8] ) -> Result<Option<String>, CollectError> {
+    match code {
+        404 | 410 => Ok(None),
+        code if code >= 200 && code < 300 => Ok(Some(String::from_utf8_lossy( body ).to_string())),
+        code => Err(CollectError::HttpStatus{ url : url.to_string(), code : code }),
+    }
+}
+
+/// Check a completed transfer's status and length
+fn check<'a>( url : &'a str, easy : &mut Easy2<Sink> ) -> Result<Download, CollectError> {
+    let transfer_error = |e : curl::Error| CollectError::Transfer{ url : url.to_string(), cause : e.to_string() };
+
+    let code = easy.response_code().map_err(&transfer_error)?;
+    if code != 0 && (code < 200 || code >= 300) {
+        return Err(CollectError::HttpStatus{ url : url.to_string(), code : code });
+    }
+
+    // curl doesn't complain about a body that's cut short on its own. What
+    // did arrive stays in part for the next try to resume from
+    let length = easy.content_length_download().map_err(&transfer_error)?;
+    let sink = easy.get_mut();
+    if length >= 0.0 && length as u64 != sink.received {
+        return Err(CollectError::LengthMismatch{ url : url.to_string(), expected : length as u64, got : sink.received });
+    }
+
+    let hasher = replace( &mut sink.hasher, Sha256::new() );
+    Ok(Download {
+        size   : sink.offset + sink.received,
+        sha256 : hex_digest( hasher ),
+        signature : None,
+    })
+}
+
+fn hex_digest( hasher : Sha256 ) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The code in a status line like HTTP/1.1 206 Partial Content
+fn parse_status( header : &[u8] ) -> Option<u32> {
+    let line = String::from_utf8_lossy( header );
+    if !line.starts_with("HTTP/") {
+        return None;
+    }
+    line.split_whitespace().nth(1).and_then(|c| c.parse::<u32>().ok())
+}
+
+/// The value of a Content-Length header
+fn parse_length( header : &[u8] ) -> Option<u64> {
+    let line = String::from_utf8_lossy( header );
+    match line.find(':') {
+        Some(i) if line[..i].eq_ignore_ascii_case("content-length") => line[i + 1..].trim().parse::<u64>().ok(),
+        _ => None,
+    }
+}
+
+/// Feed everything in f to hasher, returning how many bytes there were
+fn hash_into( f : &mut File, hasher : &mut Sha256 ) -> io::Result<u64> {
+    let mut buf = [0u8; 64 * 1024];
+    let mut total : u64 = 0;
+    loop {
+        let n = f.read( &mut buf )?;
+        if n == 0 {
+            return Ok(total);
+        }
+        hasher.update( &buf[..n] );
+        total += n as u64;
     }
-    // Data is now in dst
+}
 
+/// Check what arrived from url against what the metadata says it should be
+fn verify<'a>( url : &'a str, meta : &Metadata, expected : &Option<String>, download : &Download ) -> Result<(), CollectError> {
+    match meta.download_size {
+        Some(size) if size != download.size => {
+            return Err(CollectError::SizeMismatch{ url : url.to_string(), expected : size, got : download.size });
+        },
+        _ => {},
+    }
 
-    let target_dir : &Path = Path::new("./root/tmp/mutagen/tmp_dl");
-    if target_dir.exists() && target_dir.is_dir() {
-        let to = target_dir.join(Path::new(&pkg_name));
-        let mut file = File::create(to).unwrap();
-        file.write_all(dst.as_slice()).unwrap();
+    match *expected {
+        Some(ref want) if *want != download.sha256 => {
+            return Err(CollectError::ChecksumMismatch{ url : url.to_string(), expected : want.clone(), got : download.sha256.clone() });
+        },
+        _ => {},
     }
 
-    return true;
+    Ok(())
+}
+
+///
+/// Checksums are written sha256:<hex>, or as bare hex, which is taken to be
+/// sha256. Returns the lower case hex digest.
+///
+fn parse_checksum<'a>( checksum : &'a str ) -> Result<String, CollectError> {
+    let digest = match checksum.find(':') {
+        Some(i) if checksum[..i].eq_ignore_ascii_case("sha256") => &checksum[i + 1..],
+        Some(_) => return Err(CollectError::BadChecksum{ checksum : checksum.to_string() }),
+        None => checksum,
+    };
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(CollectError::BadChecksum{ checksum : checksum.to_string() });
+    }
+
+    Ok(digest.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST : &'static str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn parse_checksum_forms() {
+        assert_eq!( parse_checksum( DIGEST ).unwrap(), DIGEST );
+        assert_eq!( parse_checksum( &format!("sha256:{}", DIGEST) ).unwrap(), DIGEST );
+        assert_eq!( parse_checksum( &format!("SHA256:{}", DIGEST.to_uppercase()) ).unwrap(), DIGEST );
+    }
+
+    #[test]
+    fn parse_checksum_refuses() {
+        assert!( parse_checksum( &format!("md5:{}", DIGEST) ).is_err() );
+        assert!( parse_checksum( "sha256:" ).is_err() );
+        assert!( parse_checksum( &DIGEST[1..] ).is_err() );
+        assert!( parse_checksum( &format!("{}0", DIGEST) ).is_err() );
+        assert!( parse_checksum( &DIGEST.replace("5", "g") ).is_err() );
+    }
 }
@@ -1 +1,5 @@
+pub mod cache;
 pub mod collector;
+pub mod repository;
+pub mod sync;
+pub mod trust;
@@ -0,0 +1,205 @@
+extern crate toml;
+
+use std::env::current_dir;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    NoFile,
+    BadSyntax,
+    /// The base URL isn't of the form scheme://host[:port][/path]
+    BadUrl(String),
+    /// A package says it came from a repository that isn't configured
+    Unknown(String),
+    NoRepositories,
+}
+
+///
+/// Somewhere package archives can be downloaded from. Every mirror carries
+/// the same files, and they're tried in order. Mirrors can be local, as
+/// file:// URLs or plain directories, e.g. on a USB stick or an NFS share.
+///
+#[derive(Clone, Debug)]
+pub struct Repository {
+    pub name            : String,
+    // Base URLs without a trailing slash. Never empty
+    pub mirrors         : Vec<String>,
+    // Archives have to be signed by a trusted key unless this is set
+    pub allow_unsigned  : bool,
+    // Package metadata has to carry a checksum unless this is set
+    pub allow_unchecked : bool,
+}
+
+impl Repository {
+    pub fn with_mirrors<'a>( name : &'a str, urls : &[&'a str] ) -> Result<Repository, RepositoryError> {
+        if urls.len() == 0 {
+            return Err(RepositoryError::BadSyntax);
+        }
+
+        let mut mirrors : Vec<String> = vec!();
+        for url in urls.iter() {
+            mirrors.push( check_url( url )? );
+        }
+
+        Ok(Repository { name : name.to_string(), mirrors : mirrors, allow_unsigned : false, allow_unchecked : false })
+    }
+}
+
+///
+/// Check url is of the form scheme://host[:port][/path], and trim it. A
+/// plain directory is taken as a file:// URL, made absolute.
+///
+fn check_url<'a>( url : &'a str ) -> Result<String, RepositoryError> {
+    if url.starts_with('/') || url.starts_with("./") || url.starts_with("../") {
+        let dir = current_dir().map_err(|_| RepositoryError::BadUrl(url.to_string()))?.join( url );
+        return check_url( &format!("file://{}", dir.to_string_lossy()) );
+    }
+
+    let url = url.trim_end_matches('/');
+    let (scheme, rest) = match url.find("://") {
+        Some(i) => (&url[..i], &url[i + 3..]),
+        None => return Err(RepositoryError::BadUrl(url.to_string())),
+    };
+    if scheme.len() == 0 || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.') {
+        return Err(RepositoryError::BadUrl(url.to_string()));
+    }
+
+    // The host may carry a port, which has to be a number. Files can only
+    // be read from this host
+    let host = rest.split('/').next().unwrap_or("");
+    if scheme == "file" && host.len() > 0 && host != "localhost" {
+        return Err(RepositoryError::BadUrl(url.to_string()));
+    }
+    match host.rfind(':') {
+        Some(i) if host[i + 1..].parse::<u16>().is_err() => return Err(RepositoryError::BadUrl(url.to_string())),
+        _ => {},
+    }
+
+    Ok(url.to_string())
+}
+
+///
+/// The configured repositories, in order of preference. Packages that don't
+/// say which repository they came from are fetched from the first one.
+///
+pub struct Repositories {
+    repos : Vec<Repository>,
+}
+
+impl Repositories {
+    pub fn new() -> Repositories {
+        Repositories { repos : vec!() }
+    }
+
+    ///
+    /// Read repositories from a config file. A missing file just means none
+    /// are configured there.
+    ///
+    ///     [[repository]]
+    ///     name = "core"
+    ///     url = "https://example.org/mutagen/core"
+    ///
+    ///     [[repository]]
+    ///     name = "extra"
+    ///     mirrors = ["https://a.example.org/extra", "https://b.example.org/extra"]
+    ///
+    ///     [[repository]]
+    ///     name = "usb"
+    ///     url = "/media/usb/mutagen"
+    ///     allow_unsigned = true
+    ///     allow_unchecked = true
+    ///
+    pub fn load( path : &Path ) -> Result<Repositories, RepositoryError> {
+        let mut ret = Repositories::new();
+        let mut data = String::new();
+        match File::open( path ) {
+            Ok(mut f) => { f.read_to_string( &mut data ).map_err(|_| RepositoryError::NoFile)?; },
+            Err(_) => return Ok(ret),
+        }
+
+        let value = toml::Parser::new( data.as_str() ).parse().ok_or(RepositoryError::BadSyntax)?;
+        match value.get("repository") {
+            Some(r) => {
+                for entry in r.as_slice().ok_or(RepositoryError::BadSyntax)?.iter() {
+                    let name = entry.lookup("name").and_then(|n| n.as_str()).ok_or(RepositoryError::BadSyntax)?;
+                    // url is shorthand for a single mirror
+                    let mut urls : Vec<&str> = vec!();
+                    match entry.lookup("url") {
+                        Some(u) => urls.push( u.as_str().ok_or(RepositoryError::BadSyntax)? ),
+                        None => {},
+                    }
+                    match entry.lookup("mirrors") {
+                        Some(m) => {
+                            for u in m.as_slice().ok_or(RepositoryError::BadSyntax)?.iter() {
+                                urls.push( u.as_str().ok_or(RepositoryError::BadSyntax)? );
+                            }
+                        },
+                        None => {},
+                    }
+                    let mut repo = Repository::with_mirrors( name, &urls )?;
+                    match entry.lookup("allow_unsigned") {
+                        Some(a) => repo.allow_unsigned = a.as_bool().ok_or(RepositoryError::BadSyntax)?,
+                        None => {},
+                    }
+                    match entry.lookup("allow_unchecked") {
+                        Some(a) => repo.allow_unchecked = a.as_bool().ok_or(RepositoryError::BadSyntax)?,
+                        None => {},
+                    }
+                    ret.add( repo );
+                }
+            },
+            None => {},
+        }
+
+        Ok(ret)
+    }
+
+    ///
+    /// Apply a list of name=url pairs separated by whitespace or commas, as
+    /// found in the environment. Mirrors are separated by |, as in
+    /// core=http://a/core|http://b/core. These replace configured
+    /// repositories of the same name and are appended otherwise. A
+    /// replacement only moves the repository, so it keeps allow_unsigned and
+    /// allow_unchecked.
+    ///
+    pub fn apply<'a>( &mut self, pairs : &'a str ) -> Result<(), RepositoryError> {
+        for pair in pairs.split(|c : char| c == ',' || c.is_whitespace()).filter(|p| p.len() > 0) {
+            match pair.find('=') {
+                Some(i) => {
+                    let urls : Vec<&str> = pair[i + 1..].split('|').collect();
+                    let mut repo = Repository::with_mirrors( &pair[..i], &urls )?;
+                    match self.repos.iter().find(|r| r.name == repo.name) {
+                        Some(r) => { repo.allow_unsigned = r.allow_unsigned; repo.allow_unchecked = r.allow_unchecked; },
+                        None => {},
+                    }
+                    self.add( repo );
+                },
+                None => return Err(RepositoryError::BadSyntax),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add( &mut self, repo : Repository ) {
+        match self.repos.iter_mut().find(|r| r.name == repo.name) {
+            Some(r) => *r = repo,
+            None => self.repos.push(repo),
+        }
+    }
+
+    /// The repository called name, or the first one if name is None
+    pub fn get<'a>( &self, name : Option<&'a str> ) -> Result<&Repository, RepositoryError> {
+        match name {
+            Some(n) => self.repos.iter().find(|r| r.name == n).ok_or(RepositoryError::Unknown(n.to_string())),
+            None => self.repos.first().ok_or(RepositoryError::NoRepositories),
+        }
+    }
+
+    /// Every repository, in the order they were configured
+    pub fn all( &self ) -> &[Repository] {
+        &self.repos
+    }
+}
@@ -0,0 +1,412 @@
+extern crate curl;
+extern crate toml;
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::create_dir_all;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+use self::curl::easy::Easy;
+use self::curl::easy::List;
+
+use collector::collector::Attempt;
+use collector::collector::CollectError;
+use collector::collector::check_signature;
+use collector::collector::local_path;
+use collector::repository::Repository;
+use collector::trust::TrustStore;
+use solver::name::normalize;
+
+/// What every repository publishes its package metadata as
+pub const INDEX_FILE : &'static str = "index.toml";
+
+/// How a sync of one repository went
+#[derive(Debug)]
+pub enum Synced {
+    /// The index hadn't changed since last time, so nothing was downloaded
+    Unchanged,
+    /// A new index was downloaded and unpacked
+    Updated { packages : usize, removed : usize },
+}
+
+/// The validators one mirror sent with the index, to send back next time
+#[derive(Clone, Debug)]
+struct Validators {
+    etag          : Option<String>,
+    last_modified : Option<String>,
+}
+
+/// What we know about a repository's index from the last sync
+struct SyncState {
+    // Base URL of the mirror, to what it sent
+    mirrors  : BTreeMap<String, Validators>,
+    // The <name>-<version> of each package file it unpacked
+    packages : Vec<String>,
+}
+
+///
+/// Downloads repository indexes, and keeps them between runs so that a sync
+/// only downloads what changed. An index is one TOML file, index.toml at the
+/// root of each mirror, holding the metadata of every package in the
+/// repository under a table of its own:
+///
+///     [packages."vim-7.4".metadata]
+///     name = "vim"
+///     version = "7.4"
+///     [packages."vim-7.4".depends.ncurses]
+///     name = "ncurses"
+///     ...
+///
+/// Each repository gets a dir of its own holding its index and the ETag and
+/// Last-Modified each mirror sent with it, which go back as If-None-Match
+/// and If-Modified-Since. Indexes are signed like archives, as
+/// index.toml.minisig, and held to the same rules.
+///
+pub struct IndexSync {
+    dir : PathBuf,
+}
+
+impl IndexSync {
+    pub fn open( dir : &Path ) -> Result<IndexSync, CollectError> {
+        create_dir_all( dir ).map_err(|e| io_error( dir, e ))?;
+        Ok(IndexSync { dir : dir.to_path_buf() })
+    }
+
+    ///
+    /// Bring the index of repo up to date, then unpack it into the repo's
+    /// own dir in packages, the dir the resolver reads package metadata
+    /// from. See repository_packages. Mirrors are tried in order. With force
+    /// set, the index is downloaded whether or not it changed.
+    ///
+    pub fn sync( &self, repo : &Repository, trust : &TrustStore, packages : &Path, force : bool ) -> Result<Synced, CollectError> {
+        let dir = self.dir.join( dir_name( &repo.name ) );
+        create_dir_all( &dir ).map_err(|e| io_error( &dir, e ))?;
+        let index = dir.join( INDEX_FILE );
+        let state_path = dir.join("state.toml");
+
+        let mut state = load_state( &state_path )?;
+        // Validators only mean something if we still have what they're for
+        if force || !index.exists() {
+            state.mirrors.clear();
+        }
+
+        let mut attempts : Vec<Attempt> = vec!();
+        for mirror in repo.mirrors.iter() {
+            let url = format!("{}/{}", mirror, INDEX_FILE);
+            let part = dir.join( format!("{}.part", INDEX_FILE) );
+            let known = state.mirrors.get( mirror ).cloned();
+
+            let fetched = match local_path( &url ) {
+                Some(path) => copy_if_changed( &path, &part, &known ),
+                None => fetch_if_changed( &url, &part, &known ),
+            };
+            match fetched {
+                Ok(None) => {
+                    // Nothing new, but what we unpacked last time may have
+                    // been lost since
+                    let unpacked = repository_packages( packages, repo );
+                    if state.packages.iter().any(|p| !unpacked.join( format!("{}.toml", p) ).is_file()) {
+                        unpack( repo, &index, &unpacked, &mut state )?;
+                        save_state( &state_path, &state )?;
+                    }
+                    return Ok(Synced::Unchanged);
+                },
+                Ok(Some(validators)) => {
+                    match check_signature( trust, repo, &part, &url ) {
+                        Ok(_) => {},
+                        Err(e) => {
+                            let _ = remove_file( &part );
+                            attempts.push(Attempt{ url : url, attempt : 1, error : e });
+                            continue;
+                        }
+                    }
+                    rename( &part, &index ).map_err(|e| io_error( &index, e ))?;
+
+                    // Validators from other mirrors are for what they had, which
+                    // may not be what we have now
+                    state.mirrors.clear();
+                    state.mirrors.insert( mirror.clone(), validators );
+                    let (count, removed) = unpack( repo, &index, &repository_packages( packages, repo ), &mut state )?;
+                    save_state( &state_path, &state )?;
+                    return Ok(Synced::Updated{ packages : count, removed : removed });
+                },
+                Err(e) => {
+                    let _ = remove_file( &part );
+                    attempts.push(Attempt{ url : url, attempt : 1, error : e });
+                },
+            }
+        }
+
+        Err(CollectError::AllMirrorsFailed{
+            package    : INDEX_FILE.to_string(),
+            repository : repo.name.clone(),
+            attempts   : attempts,
+        })
+    }
+}
+
+///
+/// Where sync unpacks repo's packages within packages. Each repository gets
+/// a dir of its own, so two that ship the same <name>-<version> don't
+/// overwrite each other, and one can't remove what another still has.
+///
+pub fn repository_packages( packages : &Path, repo : &Repository ) -> PathBuf {
+    packages.join( dir_name( &repo.name ) )
+}
+
+///
+/// Download url to part, unless it's the same as what known was sent with.
+/// Returns the validators for what was downloaded, or None if the server
+/// said it hadn't changed.
+///
+fn fetch_if_changed<'a>( url : &'a str, part : &Path, known : &Option<Validators> ) -> Result<Option<Validators>, CollectError> {
+    let transfer_error = |e : curl::Error| CollectError::Transfer{ url : url.to_string(), cause : e.to_string() };
+
+    let mut headers = List::new();
+    match *known {
+        Some(ref v) => {
+            match v.etag {
+                Some(ref etag) => headers.append( &format!("If-None-Match: {}", etag) ).map_err(&transfer_error)?,
+                None => {},
+            }
+            match v.last_modified {
+                Some(ref date) => headers.append( &format!("If-Modified-Since: {}", date) ).map_err(&transfer_error)?,
+                None => {},
+            }
+        },
+        None => {},
+    }
+
+    let mut file = File::create( part ).map_err(|e| io_error( part, e ))?;
+    let mut write_error : Option<io::Error> = None;
+    let status : Cell<u32> = Cell::new(0);
+    let mut sent = Validators { etag : None, last_modified : None };
+
+    let mut easy = Easy::new();
+    easy.url( url ).map_err(&transfer_error)?;
+    easy.http_headers( headers ).map_err(&transfer_error)?;
+    easy.connect_timeout( Duration::from_secs(30) ).map_err(&transfer_error)?;
+    easy.low_speed_limit( 1 ).map_err(&transfer_error)?;
+    easy.low_speed_time( Duration::from_secs(30) ).map_err(&transfer_error)?;
+    let result = {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|data| {
+            let line = String::from_utf8_lossy( data );
+            if line.starts_with("HTTP/") {
+                // A new response, e.g. after a redirect
+                status.set( line.split_whitespace().nth(1).and_then(|c| c.parse::<u32>().ok()).unwrap_or(0) );
+                sent = Validators { etag : None, last_modified : None };
+            }
+            match line.find(':') {
+                Some(i) if line[..i].eq_ignore_ascii_case("etag") => sent.etag = Some(line[i + 1..].trim().to_string()),
+                Some(i) if line[..i].eq_ignore_ascii_case("last-modified") => sent.last_modified = Some(line[i + 1..].trim().to_string()),
+                _ => {},
+            }
+            true
+        }).map_err(&transfer_error)?;
+        transfer.write_function(|data| {
+            // Error pages aren't indexes
+            if status.get() < 200 || status.get() >= 300 {
+                return Ok(data.len());
+            }
+            match file.write_all( data ) {
+                Ok(_) => Ok(data.len()),
+                Err(e) => { write_error = Some(e); Ok(0) },
+            }
+        }).map_err(&transfer_error)?;
+        transfer.perform()
+    };
+    match write_error {
+        Some(e) => return Err(io_error( part, e )),
+        None => {},
+    }
+    result.map_err(&transfer_error)?;
+
+    match easy.response_code().map_err(&transfer_error)? {
+        304 => { let _ = remove_file( part ); Ok(None) },
+        code if code >= 200 && code < 300 => Ok(Some(sent)),
+        code => Err(CollectError::HttpStatus{ url : url.to_string(), code : code }),
+    }
+}
+
+///
+/// The local equivalent of fetch_if_changed. There are no validators to be
+/// sent, so the file's size and modification time stand in for an ETag.
+///
+fn copy_if_changed( path : &Path, part : &Path, known : &Option<Validators> ) -> Result<Option<Validators>, CollectError> {
+    let read_error = |e : io::Error| CollectError::Read{ path : path.to_string_lossy().to_string(), cause : e.to_string() };
+
+    let meta = path.metadata().map_err(&read_error)?;
+    let mtime = meta.modified().ok().and_then(|t| t.duration_since( UNIX_EPOCH ).ok())
+                    .map(|d| d.as_secs() * 1000000000 + d.subsec_nanos() as u64).unwrap_or(0);
+    let etag = format!("\"{}-{}\"", meta.len(), mtime);
+    match *known {
+        Some(Validators{ etag : Some(ref e), .. }) if *e == etag => return Ok(None),
+        _ => {},
+    }
+
+    let mut from = File::open( path ).map_err(&read_error)?;
+    let mut to = File::create( part ).map_err(|e| io_error( part, e ))?;
+    io::copy( &mut from, &mut to ).map_err(&read_error)?;
+    Ok(Some(Validators { etag : Some(etag), last_modified : None }))
+}
+
+///
+/// Write every package in index to its own file in packages, as the
+/// resolver expects, and remove the ones this repository had last time but
+/// doesn't any more. Packages that don't name a repository are marked as
+/// coming from this one. Returns how many were written and removed.
+///
+fn unpack( repo : &Repository, index : &Path, packages : &Path, state : &mut SyncState ) -> Result<(usize, usize), CollectError> {
+    let bad = || CollectError::Read{ path : index.to_string_lossy().to_string(), cause : "not a valid index".to_string() };
+
+    let mut data = String::new();
+    File::open( index ).and_then(|mut f| f.read_to_string( &mut data )).map_err(|e| io_error( index, e ))?;
+    let value = toml::Parser::new( data.as_str() ).parse().ok_or_else(&bad)?;
+    let entries = match value.get("packages") {
+        Some(p) => p.as_table().ok_or_else(&bad)?.clone(),
+        None => toml::Table::new(),
+    };
+
+    // Check everything before writing anything, so a bad index can't leave
+    // the package dir half updated
+    let mut files : Vec<(String, toml::Table)> = vec!();
+    for (_, entry) in entries.into_iter() {
+        let mut entry = match entry {
+            toml::Value::Table(t) => t,
+            _ => return Err(bad()),
+        };
+        let file = {
+            let meta = entry.get_mut("metadata").and_then(|m| match *m {
+                toml::Value::Table(ref mut t) => Some(t),
+                _ => None,
+            }).ok_or_else(&bad)?;
+            let name = meta.get("name").and_then(|n| n.as_str()).ok_or_else(&bad)?.to_string();
+            let version = meta.get("version").and_then(|v| v.as_str()).ok_or_else(&bad)?.to_string();
+
+            // These become a file name, so they mustn't be able to point
+            // anywhere else
+            let name = normalize( &name ).map_err(|_| bad())?;
+            if version.len() == 0 || version.starts_with('.') || version.contains('/') {
+                return Err(bad());
+            }
+
+            if !meta.contains_key("repository") {
+                meta.insert("repository".to_string(), toml::Value::String(repo.name.clone()));
+            }
+            format!("{}-{}", name, version)
+        };
+        files.push((file, entry));
+    }
+
+    create_dir_all( packages ).map_err(|e| io_error( packages, e ))?;
+    for &(ref file, ref entry) in files.iter() {
+        let path = packages.join( format!("{}.toml", file) );
+        write_file( &path, toml::Value::Table(entry.clone()).to_string().as_bytes() )?;
+    }
+
+    let current : Vec<String> = files.into_iter().map(|(f, _)| f).collect();
+    let mut removed = 0;
+    for old in state.packages.iter().filter(|p| !current.contains( p )) {
+        let path = packages.join( format!("{}.toml", old) );
+        if path.exists() {
+            remove_file( &path ).map_err(|e| io_error( &path, e ))?;
+            removed += 1;
+        }
+    }
+
+    state.packages = current;
+    Ok((state.packages.len(), removed))
+}
+
+/// Repository names are free-form, so keep them to something safe as a dir
+fn dir_name<'a>( name : &'a str ) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+///
+///     packages = ["vim-7.4", ...]
+///
+///     [mirrors."https://example.org/core"]
+///     etag = "\"5d8c72a5edda8\""
+///     last_modified = "Wed, 21 Oct 2015 07:28:00 GMT"
+///
+fn load_state( path : &Path ) -> Result<SyncState, CollectError> {
+    let mut ret = SyncState { mirrors : BTreeMap::new(), packages : vec!() };
+    let bad = || CollectError::Read{ path : path.to_string_lossy().to_string(), cause : "bad syntax".to_string() };
+
+    let mut data = String::new();
+    match File::open( path ) {
+        Ok(mut f) => { f.read_to_string( &mut data ).map_err(|e| io_error( path, e ))?; },
+        Err(_) => return Ok(ret),
+    }
+    let value = toml::Parser::new( data.as_str() ).parse().ok_or_else(&bad)?;
+
+    match value.get("packages").and_then(|p| p.as_slice()) {
+        Some(packages) => {
+            for p in packages.iter() {
+                ret.packages.push( p.as_str().ok_or_else(&bad)?.to_string() );
+            }
+        },
+        None => {},
+    }
+    match value.get("mirrors").and_then(|m| m.as_table()) {
+        Some(mirrors) => {
+            for (url, v) in mirrors.iter() {
+                ret.mirrors.insert( url.clone(), Validators {
+                    etag          : v.lookup("etag").and_then(|e| e.as_str()).map(|e| e.to_string()),
+                    last_modified : v.lookup("last_modified").and_then(|l| l.as_str()).map(|l| l.to_string()),
+                });
+            }
+        },
+        None => {},
+    }
+
+    Ok(ret)
+}
+
+fn save_state( path : &Path, state : &SyncState ) -> Result<(), CollectError> {
+    let mut mirrors = toml::Table::new();
+    for (url, v) in state.mirrors.iter() {
+        let mut entry = toml::Table::new();
+        match v.etag {
+            Some(ref e) => { entry.insert("etag".to_string(), toml::Value::String(e.clone())); },
+            None => {},
+        }
+        match v.last_modified {
+            Some(ref l) => { entry.insert("last_modified".to_string(), toml::Value::String(l.clone())); },
+            None => {},
+        }
+        mirrors.insert( url.clone(), toml::Value::Table(entry) );
+    }
+
+    let mut root = toml::Table::new();
+    root.insert("packages".to_string(), toml::Value::Array(state.packages.iter().map(|p| toml::Value::String(p.clone())).collect()));
+    root.insert("mirrors".to_string(), toml::Value::Table(mirrors));
+
+    write_file( path, toml::Value::Table(root).to_string().as_bytes() )
+}
+
+/// Write data to path.part and move it over path, so readers never see
+/// half a file
+fn write_file( path : &Path, data : &[u8] ) -> Result<(), CollectError> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from( part );
+    let mut f = File::create( &part ).map_err(|e| io_error( &part, e ))?;
+    f.write_all( data ).map_err(|e| io_error( &part, e ))?;
+    rename( &part, path ).map_err(|e| io_error( path, e ))
+}
+
+fn io_error( path : &Path, e : io::Error ) -> CollectError {
+    CollectError::Io{ path : path.to_string_lossy().to_string(), cause : e.to_string() }
+}
@@ -0,0 +1,143 @@
+extern crate minisign_verify;
+
+use std::fmt;
+use std::fs::File;
+use std::fs::read_dir;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use self::minisign_verify::Error as MinisignError;
+use self::minisign_verify::PublicKey;
+use self::minisign_verify::Signature;
+
+#[derive(Debug)]
+pub enum TrustError {
+    Io { path : String, cause : String },
+    /// A file in the trust store isn't a minisign public key
+    BadKey(String),
+}
+
+impl fmt::Display for TrustError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        match *self {
+            TrustError::Io{ ref path, ref cause } => write!(f, "could not read {}: {}", path, cause),
+            TrustError::BadKey(ref path) => write!(f, "{} is not a minisign public key", path),
+        }
+    }
+}
+
+/// Why a signature wasn't accepted
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The .minisig file is malformed, or uses an algorithm we don't know
+    BadEncoding,
+    /// Made with a key that isn't in the trust store
+    UnknownKey,
+    /// Made with a trusted key, but not over this content
+    Invalid,
+    Io(String),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        match *self {
+            SignatureError::BadEncoding => write!(f, "the signature is malformed"),
+            SignatureError::UnknownKey => write!(f, "the signature was made with a key that isn't trusted"),
+            SignatureError::Invalid => write!(f, "the signature does not match"),
+            SignatureError::Io(ref cause) => write!(f, "could not check the signature: {}", cause),
+        }
+    }
+}
+
+///
+/// The public keys whose signatures we accept, as minisign.pub files in a
+/// directory. Any of them can sign for any repository.
+///
+pub struct TrustStore {
+    // By file name, for telling people which key signed what
+    keys : Vec<(String, PublicKey)>,
+}
+
+impl TrustStore {
+    pub fn new() -> TrustStore {
+        TrustStore { keys : vec!() }
+    }
+
+    /// Read every *.pub file in dir. A missing dir means no keys are trusted
+    pub fn load( dir : &Path ) -> Result<TrustStore, TrustError> {
+        let mut ret = TrustStore::new();
+        let io_error = |path : &Path, e : io::Error| TrustError::Io{ path : path.to_string_lossy().to_string(), cause : e.to_string() };
+
+        let entries = match read_dir( dir ) {
+            Ok(e) => e,
+            Err(_) => return Ok(ret),
+        };
+        let mut paths = vec!();
+        for entry in entries {
+            let path = entry.map_err(|e| io_error( dir, e ))?.path();
+            match path.extension() {
+                Some(e) if e == "pub" => paths.push( path ),
+                _ => {},
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            let mut data = String::new();
+            File::open( &path ).and_then(|mut f| f.read_to_string( &mut data )).map_err(|e| io_error( &path, e ))?;
+            let key = PublicKey::decode( data.trim() ).map_err(|_| TrustError::BadKey(path.to_string_lossy().to_string()))?;
+            ret.add( &path.file_stem().unwrap().to_string_lossy(), key );
+        }
+
+        Ok(ret)
+    }
+
+    pub fn add<'a>( &mut self, name : &'a str, key : PublicKey ) {
+        self.keys.push( (name.to_string(), key) );
+    }
+
+    ///
+    /// Check signature, the contents of a .minisig file, against the file at
+    /// path. Returns the name of the key that made it.
+    ///
+    pub fn verify<'a>( &self, path : &Path, signature : &'a str ) -> Result<String, SignatureError> {
+        let signature = Signature::decode( signature.trim() ).map_err(|_| SignatureError::BadEncoding)?;
+
+        for &(ref name, ref key) in self.keys.iter() {
+            match verify_with( key, &signature, path ) {
+                Ok(_) => return Ok(name.clone()),
+                Err(MinisignError::UnexpectedKeyId) => continue,
+                Err(MinisignError::IoError(e)) => return Err(SignatureError::Io(e.to_string())),
+                Err(_) => return Err(SignatureError::Invalid),
+            }
+        }
+
+        Err(SignatureError::UnknownKey)
+    }
+}
+
+/// Verify the file at path, a chunk at a time unless it's an old-style
+/// signature over the whole file
+fn verify_with( key : &PublicKey, signature : &Signature, path : &Path ) -> Result<(), MinisignError> {
+    let mut f = File::open( path )?;
+    match key.verify_stream( signature ) {
+        Ok(mut stream) => {
+            let mut buf = [0u8; 64 * 1024];
+            loop {
+                let n = f.read( &mut buf )?;
+                if n == 0 {
+                    break;
+                }
+                stream.update( &buf[..n] );
+            }
+            stream.finalize()
+        },
+        Err(MinisignError::UnsupportedLegacyMode) => {
+            let mut data = vec!();
+            f.read_to_end( &mut data )?;
+            key.verify( &data, signature, true )
+        },
+        Err(e) => Err(e),
+    }
+}
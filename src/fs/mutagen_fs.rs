@@ -27,6 +27,10 @@ ReplyDirectory, ReplyEmpty, ReplyOpen};
 pub enum MutagenFilesystemError {
     FileDoesNotExist,
     DirDoesNotExist,
+    // Two packages both want to put something at path
+    FileConflict { path : PathBuf, owner : Tag },
+    // Something under a package's directory could not be read
+    Unreadable { path : PathBuf, cause : String },
 }
 
 pub enum Type {
@@ -34,10 +38,13 @@ pub enum Type {
     File,
 }
 
-#[derive (Clone)]
+#[derive (Clone, Debug)]
 pub struct Tag {
     pub owner_name      : String,
     pub owner_version   : String,
+    // Set for packages installed into a slot, which can sit next to other
+    // versions of the same package
+    pub owner_slot      : Option<String>,
 }
 
 
@@ -162,12 +169,12 @@ impl MutagenFilesystem {
 
         // Get the DirEntry represented by this ino
         // Insert a new record into the DirEntry. If it already exists, there's
-        // a shared dir
+        // a shared dir, and we hand back the inode it already has
         match self.dir_vfs.entry(parent_ino) {
             Occupied(mut d) => {
                 let parent = d.get_mut();
                 match parent.entries.entry(name){
-                    Occupied(o) => (),
+                    Occupied(o) => return o.get().ino,
                     Vacant(v) => {
                         let e = Entry{
                             ino : self.ino_counter,
@@ -206,7 +213,9 @@ impl MutagenFilesystem {
 
 
     fn load_file(&mut self, name : OsString, parent_dir : PathBuf, true_path : PathBuf, tag : Tag, entry : DirEntry ) {
+        let full_path = parent_dir.join(Path::new(&name));
         let ino = self.map_inode( parent_dir, Type::Dir, name );
+        self.mapping.insert( full_path, ino );
 
         match self.file_vfs.entry(ino){
             Occupied(o) => panic!("File conflict"),
@@ -221,15 +230,71 @@ impl MutagenFilesystem {
     }
 
 
+    /**
+     * Find everything under p that would land on top of a file some other
+     * package already put into this filesystem, or a directory on top of
+     * a file, or a file on top of a directory
+     */
+    pub fn conflicts(&self, p : &Path) -> Result<Vec<(PathBuf, Tag)>, MutagenFilesystemError> {
+        let mut ret = vec!();
+        for entry in WalkDir::new( p ).min_depth( 1 ) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(MutagenFilesystemError::Unreadable{
+                    path : e.path().unwrap_or( p ).to_path_buf(),
+                    cause : e.to_string(),
+                }),
+            };
+            let rel = match entry.path().strip_prefix( p ) {
+                Ok(r) => r.to_path_buf(),
+                Err(_) => return Err(MutagenFilesystemError::Unreadable{
+                    path : entry.path().to_path_buf(),
+                    cause : format!("not under {}", p.display()),
+                }),
+            };
+            let ino = match self.mapping.get( &rel ) {
+                Some(ino) => *ino,
+                None => continue,
+            };
+
+            // Sharing a directory is fine, it's the whole point
+            if entry.path().is_dir() && self.is_dir( ino ) {
+                continue;
+            }
+
+            match self.file_vfs.get( &ino ) {
+                Some(f) => ret.push((rel, f.tag.clone())),
+                None => ret.push((rel, Tag{
+                    owner_name : "(directory)".to_string(),
+                    owner_version : String::new(),
+                    owner_slot : None,
+                })),
+            }
+        }
+        return Ok(ret);
+    }
+
     /**
      * Provided with a path (presumably containing a package), index it into
-     * this filesystem under the provided tag
+     * this filesystem under the provided tag. Nothing is added if any of
+     * its files collide with what's already here.
      */
-    pub fn inject(&mut self, p : &Path, tag : Tag){
+    pub fn inject(&mut self, p : &Path, tag : Tag) -> Result<(), MutagenFilesystemError> {
+        match self.conflicts( p )?.into_iter().next() {
+            Some((path, owner)) => return Err(MutagenFilesystemError::FileConflict{ path : path, owner : owner }),
+            None => {},
+        }
+
         // Walk the new path
         for entry in WalkDir::new( p ) {
 
-            let entry = entry.unwrap();
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(MutagenFilesystemError::Unreadable{
+                    path : e.path().unwrap_or( p ).to_path_buf(),
+                    cause : e.to_string(),
+                }),
+            };
             let entry_clone = entry.clone();
             let entry_path = entry_clone.path();
             let true_path;
@@ -282,6 +347,8 @@ impl MutagenFilesystem {
 
 
         }
+
+        Ok(())
     }
 
     pub fn remove(&mut self, tag : Tag){
@@ -1,6 +1,23 @@
 mod solver;
 use solver::context::Context;
+use solver::context::GROUP_PREFIX;
+use solver::context::Reason;
+use solver::context::Replacement;
+use solver::export;
+use solver::name::node_key;
+use solver::name::split_key;
+use solver::legacy_resolver::VenomResolver;
+use solver::legacy_resolver::WeaveResolver;
 use solver::package_resolver::FilesystemResolver;
+use solver::package_resolver::DependencyKind;
+use solver::package_resolver::Metadata;
+use solver::package_resolver::Resolver;
+use solver::package_resolver::ResolverError;
+use solver::package_resolver::to_toml;
+use solver::version::Version;
+use solver::prefetch_resolver::PrefetchResolver;
+use solver::disk_cache::DiskCacheResolver;
+use solver::policy::Blocklist;
 
 
 mod archive;
@@ -10,42 +27,743 @@ mod fs;
 use fs::mutagen_fs::MutagenFilesystem;
 use fs::mutagen_fs::Tag;
 use std::path::Path;
+use std::path::PathBuf;
 
 mod collector;
-use collector::collector::collect_package;
+use collector::cache::CleanPolicy;
+use collector::cache::PackageCache;
+use collector::collector::CollectError;
+use collector::collector::Collector;
+use collector::collector::Limits;
+use collector::repository::Repositories;
+use collector::sync::IndexSync;
+use collector::sync::Synced;
+use collector::sync::repository_packages;
+use collector::trust::TrustStore;
 
+mod progress;
+use progress::Progress;
+use progress::Silent;
+use progress::human_size;
+use progress::json::JsonProgress;
+use progress::terminal::TerminalProgress;
+
+mod state;
+use state::installed::InstalledState;
+
+use std::fs::File;
 use std::fs::create_dir_all;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::env::consts::ARCH;
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 
 extern crate fuse;
+extern crate libc;
+
+/// Where downloaded package archives are kept between runs
+const PACKAGE_CACHE : &'static str = "./root/var/cache/mutagen/packages";
+
+/// Where parsed package metadata is kept between runs
+const METADATA_CACHE : &'static str = "./root/var/cache/mutagen/metadata";
+
+/// The record of what is installed
+const INSTALLED_STATE : &'static str = "./root/var/lib/mutagen/installed.toml";
+
+/// General configuration, including the repositories to download from
+const CONFIG : &'static str = "./mutagenrc.toml";
+
+/// name=url pairs that override or add to the configured repositories
+const REPOSITORIES_ENV : &'static str = "MUTAGEN_REPOSITORIES";
+
+/// How to report download and extraction progress: "bar" (the default),
+/// "json" for JSON lines on stderr, or "none"
+const PROGRESS_ENV : &'static str = "MUTAGEN_PROGRESS";
+
+/// Repository indexes, and what each mirror said about them, between syncs
+const INDEX_STATE : &'static str = "./root/var/lib/mutagen/sync";
+
+/// Where package metadata is read from. Synced indexes are unpacked to a
+/// dir per repository in here
+const PACKAGE_METADATA : &'static str = "./pkg";
+
+/// Minisign public keys whose package signatures are trusted, as *.pub
+const TRUSTED_KEYS : &'static str = "./root/etc/mutagen/trusted.d";
+
+/// Packages and licenses the administrator won't allow, if present
+const BLOCKLIST : &'static str = "./root/etc/mutagen/blocklist.toml";
+
+/// What ^C sets while packages are downloading
+static INTERRUPT : OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// The resolver stack every solve goes through
+type RepoResolver = PrefetchResolver<DiskCacheResolver<FilesystemResolver>>;
 
 fn main() {
+    let args : Vec<String> = env::args().collect();
+    let mut argv : Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+    // Minimal images can skip recommended packages for any command
+    let recommends = !argv.contains(&"--no-recommends");
+    argv.retain(|a| *a != "--no-recommends");
+
+    match &argv[1..] {
+        &["install", group] if group.starts_with(GROUP_PREFIX) => install_group(group, false, recommends),
+        &["install", "--select", group] if group.starts_with(GROUP_PREFIX) => install_group(group, true, recommends),
+        &["install", name, version] => install(solve(name, version, "runtime", recommends)),
+        &["install", "--kinds", kinds, name, version] => install(solve(name, version, kinds, recommends)),
+        &["upgrade"] => upgrade(recommends),
+        &["info", name, version] => info(name, version),
+        &["search", term] => search(term),
+        &["why", name, version, target] => why(name, version, target, recommends),
+        &["dependents", name, version, target] => dependents(name, version, target, recommends),
+        &["graph", "dot", name, version] => print!("{}", export::to_dot(&solve(name, version, "runtime", recommends).map)),
+        &["graph", "json", name, version] => println!("{}", export::to_json(&solve(name, version, "runtime", recommends).map)),
+        &["convert", "weave", dir] => convert(&WeaveResolver{ manifest_dir : dir.to_string() }, WeaveResolver::packages),
+        &["convert", "venom", dir] => convert(&VenomResolver{ repo_dir : dir.to_string() }, VenomResolver::packages),
+        &["sync"] | &["refresh"] => sync(false),
+        &["sync", "--force"] | &["refresh", "--force"] => sync(true),
+        &["cache", "list"] => cache_list(),
+        &["cache", "clean", ref options @ ..] => cache_clean(options),
+        _ => {
+            println!("Usage: mutagen install [--no-recommends] [--kinds runtime,build,check,optional,recommends] <name[:slot]> <version>");
+            println!("       mutagen install [--no-recommends] [--select] @<group>");
+            println!("       mutagen upgrade [--no-recommends]");
+            println!("       mutagen info <name> <version>");
+            println!("       mutagen search <term>");
+            println!("       mutagen why <name> <version> <package>");
+            println!("       mutagen dependents <name> <version> <package>");
+            println!("       mutagen graph <dot|json> <name> <version>");
+            println!("       mutagen convert <weave|venom> <dir>");
+            println!("       mutagen sync [--force]");
+            println!("       mutagen cache list");
+            println!("       mutagen cache clean [--keep-versions <n>] [--max-size <size>[K|M|G]] [--uninstalled]");
+            exit(1);
+        }
+    }
+}
+
+fn info( name : &str, version : &str ) {
+    let resolver = new_resolver();
+    match resolver.resolve( name, version ) {
+        Ok(meta) => print_info(&meta),
+        Err(e) => {
+            println!("Could not resolve {}-{}: {:?}", name, version, e);
+            exit(1);
+        }
+    }
+}
+
+fn search( term : &str ) {
+    let term = term.to_lowercase();
+    let resolver = new_resolver();
+    for meta in resolver.list() {
+        let matches = meta.name.to_lowercase().contains(&term) ||
+                      meta.groups.iter().any(|g| g.to_lowercase() == term) ||
+                      match meta.description {
+                          Some(ref d) => d.to_lowercase().contains(&term),
+                          None => false,
+                      };
+
+        if matches {
+            println!("{} {} ({})", meta.name, meta.version, meta.arch);
+            match meta.description {
+                Some(ref d) => println!("    {}", d),
+                None => {},
+            }
+        }
+    }
+}
+
+///
+/// Bring every package in a weave or venom repository over into pkg/, so
+/// the rest of mutagen can solve and install them
+///
+fn convert<R : Resolver>( resolver : &R, packages : fn(&R) -> Result<Vec<(String, String)>, ResolverError> ) {
+    let packages = match packages(resolver) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Could not list the repository: {:?}", e);
+            exit(1);
+        }
+    };
+
+    match create_dir_all(PACKAGE_METADATA) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Could not create {}: {}", PACKAGE_METADATA, e);
+            exit(1);
+        }
+    }
+
+    let mut failed = false;
+    for (name, version) in packages {
+        let meta = match resolver.resolve(&name, &version) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Could not convert {}-{}: {:?}", name, version, e);
+                failed = true;
+                continue;
+            }
+        };
+
+        let path = Path::new(PACKAGE_METADATA).join(format!("{}-{}.toml", meta.name, meta.version));
+        match File::create(&path).and_then(|mut f| f.write_all(to_toml(&meta).as_bytes())) {
+            Ok(_) => println!("Converted {}-{}", meta.name, meta.version),
+            Err(e) => {
+                println!("Could not write {}: {}", path.display(), e);
+                exit(1);
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn print_info( meta : &Metadata ) {
+    let unknown = "-".to_string();
+    println!("Name           : {}", meta.name);
+    println!("Version        : {}", meta.version);
+    println!("Description    : {}", meta.description.as_ref().unwrap_or(&unknown));
+    println!("Architecture   : {}", meta.arch);
+    println!("Slot           : {}", meta.slot.as_ref().unwrap_or(&unknown));
+    println!("License        : {}", meta.license.as_ref().unwrap_or(&unknown));
+    println!("Homepage       : {}", meta.homepage.as_ref().unwrap_or(&unknown));
+    println!("Maintainer     : {}", meta.maintainer.as_ref().unwrap_or(&unknown));
+    println!("Groups         : {}", meta.groups.join(" "));
+    println!("Obsoletes      : {}", meta.obsoletes.join(" "));
+    println!("Meta-package   : {}", if meta.meta_package { "yes" } else { "no" });
+    println!("Depends On     : {}", meta.deps.iter()
+                                        .map(|d| (node_key(&d.name, d.slot.as_ref().map(|s| s.as_str())), d))
+                                        .map(|(n, d)| match d.kind {
+                                            DependencyKind::Runtime => format!("{}>={}<={}", n, d.min_version, d.max_version),
+                                            _ => format!("{}>={}<={}[{}]", n, d.min_version, d.max_version, d.kind.as_str()),
+                                        })
+                                        .collect::<Vec<String>>()
+                                        .join(" "));
+    println!("Download Size  : {}", meta.download_size.map(|s| s.to_string()).unwrap_or(unknown.clone()));
+    println!("Installed Size : {}", meta.installed_size.map(|s| s.to_string()).unwrap_or(unknown.clone()));
+    println!("Checksum       : {}", meta.checksum.as_ref().unwrap_or(&unknown));
+    println!("Build Date     : {}", meta.build_date.as_ref().unwrap_or(&unknown));
+    println!("Repository     : {}", meta.repository.as_ref().unwrap_or(&unknown));
+}
+
+fn why( name : &str, version : &str, target : &str, recommends : bool ) {
+    let c = solve(name, version, "runtime", recommends);
+    match c.why(target) {
+        Some(chain) => println!("{}", chain.join(" -> ")),
+        None => {
+            println!("{} is not required by {}-{}", target, name, version);
+            exit(1);
+        }
+    }
+}
+
+fn dependents( name : &str, version : &str, target : &str, recommends : bool ) {
+    let c = solve(name, version, "runtime", recommends);
+    for d in c.dependents(target) {
+        println!("{}", d);
+    }
+}
+
+fn parse_kinds( kinds : &str ) -> Vec<DependencyKind> {
+    let mut follow : Vec<DependencyKind> = vec!();
+    for k in kinds.split(',') {
+        match DependencyKind::from_str(k) {
+            Some(kind) => follow.push(kind),
+            None => {
+                println!("Unknown dependency kind {}", k);
+                exit(1);
+            }
+        }
+    }
+
+    return follow;
+}
+
+fn new_context( kinds : &str, recommends : bool ) -> Context<RepoResolver> {
+    let resolver = DiskCacheResolver::new(new_resolver(), Path::new(METADATA_CACHE), "packages");
+    let mut c = Context::new(PrefetchResolver::new(resolver), ARCH);
+    c.follow(&parse_kinds(kinds));
+    c.install_recommends(recommends);
+
+    let blocklist = Path::new(BLOCKLIST);
+    if blocklist.exists() {
+        match Blocklist::load(blocklist) {
+            Ok(b) => c.add_policy(Box::new(b)),
+            Err(e) => {
+                println!("Could not read {}: {:?}", BLOCKLIST, e);
+                exit(1);
+            }
+        }
+    }
+    return c;
+}
+
+fn load_repositories() -> Repositories {
+    let mut repos = match Repositories::load(Path::new(CONFIG)) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Could not read {}: {:?}", CONFIG, e);
+            exit(1);
+        }
+    };
+
+    match env::var(REPOSITORIES_ENV) {
+        Ok(pairs) => match repos.apply(&pairs) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("Could not use {}: {:?}", REPOSITORIES_ENV, e);
+                exit(1);
+            }
+        },
+        Err(_) => {},
+    }
+
+    return repos;
+}
+
+///
+/// Read package metadata from each repository's synced index, in order of
+/// preference, then from whatever was put in PACKAGE_METADATA itself
+///
+fn new_resolver() -> FilesystemResolver {
+    let packages = Path::new(PACKAGE_METADATA);
+    let mut dirs : Vec<PathBuf> = load_repositories().all().iter().map(|r| repository_packages(packages, r)).collect();
+    dirs.push(packages.to_path_buf());
+    FilesystemResolver::new(dirs)
+}
+
+fn new_progress() -> Box<dyn Progress> {
+    match env::var(PROGRESS_ENV).as_ref().map(|p| p.as_str()) {
+        Ok("json") => Box::new(JsonProgress::new(io::stderr())),
+        Ok("none") => Box::new(Silent),
+        Ok("bar") | Err(_) => Box::new(TerminalProgress::new()),
+        Ok(other) => {
+            println!("Unknown {} {}, expected bar, json or none", PROGRESS_ENV, other);
+            exit(1);
+        }
+    }
+}
+
+fn open_cache() -> PackageCache {
+    match PackageCache::open(Path::new(PACKAGE_CACHE)) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Could not open the package cache: {}", e);
+            exit(1);
+        }
+    }
+}
+
+///
+/// Bring every repository's index up to date. Indexes that haven't changed
+/// since the last sync aren't downloaded again, unless force is set.
+///
+fn sync( force : bool ) {
+    let repos = load_repositories();
+    let trust = match TrustStore::load(Path::new(TRUSTED_KEYS)) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Could not load trusted keys: {}", e);
+            exit(1);
+        }
+    };
+    let indexes = match IndexSync::open(Path::new(INDEX_STATE)) {
+        Ok(i) => i,
+        Err(e) => {
+            println!("Could not open {}: {}", INDEX_STATE, e);
+            exit(1);
+        }
+    };
+
+    let mut failed = false;
+    for repo in repos.all() {
+        match indexes.sync(repo, &trust, Path::new(PACKAGE_METADATA), force) {
+            Ok(Synced::Unchanged) => println!("{} is up to date", repo.name),
+            Ok(Synced::Updated{ packages, removed }) =>
+                println!("{}: {} packages, {} removed", repo.name, packages, removed),
+            Err(e) => {
+                println!("Could not sync {}: {}", repo.name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn cache_list() {
+    let entries = match open_cache().entries() {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Could not read the package cache: {}", e);
+            exit(1);
+        }
+    };
+
+    for e in entries.iter() {
+        println!("{} {} {} {}{}", e.name, e.version, human_size(e.size), e.sha256, if e.signed { " signed" } else { "" });
+    }
+    println!("{} archives, {}", entries.len(), human_size(entries.iter().map(|e| e.size).sum()));
+}
+
+///
+/// Remove archives from the cache according to the options, which can be
+/// combined. With none, only unfinished downloads are removed.
+///
+fn cache_clean( options : &[&str] ) {
+    let usage = || -> ! {
+        println!("Usage: mutagen cache clean [--keep-versions <n>] [--max-size <size>[K|M|G]] [--uninstalled]");
+        exit(1);
+    };
+
+    let mut policy = CleanPolicy::new();
+    let mut i = 0;
+    while i < options.len() {
+        match (options[i], options.get(i + 1)) {
+            ("--keep-versions", Some(n)) => { policy.keep_versions = Some(n.parse().unwrap_or_else(|_| usage())); i += 1; },
+            ("--max-size", Some(s)) => { policy.max_size = Some(parse_size(s).unwrap_or_else(|| usage())); i += 1; },
+            ("--uninstalled", _) => policy.uninstalled = true,
+            _ => usage(),
+        }
+        i += 1;
+    }
+
+    // Archives are kept per package, so slots don't matter here
+    let installed : Vec<(String, String)> = load_state().packages.values()
+        .map(|p| (split_key(&p.name).0.to_string(), p.version.clone()))
+        .collect();
 
+    match open_cache().clean(&policy, &installed) {
+        Ok(removed) => {
+            for e in removed.iter() {
+                println!("Removed {}-{} ({})", e.name, e.version, human_size(e.size));
+            }
+            println!("Freed {}", human_size(removed.iter().map(|e| e.size).sum()));
+        },
+        Err(e) => {
+            println!("Could not clean the package cache: {}", e);
+            exit(1);
+        }
+    }
+}
+
+/// A size in bytes, optionally followed by K, M or G
+fn parse_size( s : &str ) -> Option<u64> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let multiplier : u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier))
+}
+
+fn load_state() -> InstalledState {
+    match InstalledState::load(Path::new(INSTALLED_STATE)) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Could not read {}: {:?}", INSTALLED_STATE, e);
+            exit(1);
+        }
+    }
+}
+
+fn save_state( state : &InstalledState ) {
+    match state.save(Path::new(INSTALLED_STATE)) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Could not write {}: {:?}", INSTALLED_STATE, e);
+            exit(1);
+        }
+    }
+}
+
+///
+/// Solve for name-version, following the comma separated dependency kinds,
+/// exiting if that isn't possible
+///
+fn solve( name : &str, version : &str, kinds : &str, recommends : bool ) -> Context<RepoResolver> {
     // We first identify the list of dependencies we need to install for this
     // package
-    let mut c = Context::new(FilesystemResolver{});
-    c.inject("vim".to_string(), "7.4.1386-1".to_string());
+    let mut c = new_context(kinds, recommends);
+    match c.inject(name.to_string(), version.to_string()) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Could not solve for {}-{}: {}", name, version, e);
+            exit(1);
+        }
+    }
+    print_warnings(&c);
+
+    return c;
+}
+
+///
+/// Install every member of a group, or with select, the ones picked at a
+/// prompt
+///
+fn install_group( group : &str, select : bool, recommends : bool ) {
+    let mut c = new_context("runtime", recommends);
+    if select {
+        c.select_with(Box::new(prompt_members));
+    }
+    match c.inject(group.to_string(), String::new()) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("Could not solve for {}: {}", group, e);
+            exit(1);
+        }
+    }
+    print_warnings(&c);
+
+    install(c);
+}
+
+///
+/// Ask which members of group to install, pacman style: a list of numbers
+/// and ranges like 1 3-5, where nothing at all means everything
+///
+fn prompt_members<'a>( group : &'a str, members : &[Metadata] ) -> Vec<String> {
+    println!("There are {} members in group {}:", members.len(), group);
+    for (i, m) in members.iter().enumerate() {
+        println!("   {}) {} {}", i + 1, m.name, m.version);
+    }
+
+    loop {
+        print!("Enter a selection (default=all): ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            exit(1);
+        }
+        if line.trim().len() == 0 {
+            return members.iter().map(|m| m.name.clone()).collect();
+        }
+
+        match parse_selection(line.trim(), members.len()) {
+            Some(picked) => return picked.iter().map(|i| members[*i].name.clone()).collect(),
+            None => println!("Invalid selection"),
+        }
+    }
+}
+
+/// Turn 1 3-5 (or 1,3-5) into zero based indices below count
+fn parse_selection<'a>( line : &'a str, count : usize ) -> Option<Vec<usize>> {
+    let mut ret : Vec<usize> = vec!();
+    for part in line.split(|c : char| c == ',' || c.is_whitespace()).filter(|p| p.len() > 0) {
+        let (first, last) = match part.find('-') {
+            Some(i) => (part[..i].parse::<usize>().ok()?, part[i + 1..].parse::<usize>().ok()?),
+            None => (part.parse::<usize>().ok()?, part.parse::<usize>().ok()?),
+        };
+        if first == 0 || first > last || last > count {
+            return None;
+        }
+        ret.extend((first - 1)..last);
+    }
+
+    return Some(ret);
+}
+
+fn install( c : Context<RepoResolver> ) {
+    let dependencies = c.flatten("ROOT".to_string());
+    let fs = deploy(c.resolver(), &dependencies);
+
+    // Only record what actually made it onto the system
+    let mut state = load_state();
+    state.record_solve(&dependencies, &[], false);
+    save_state(&state);
+
+    mount(fs);
+}
+
+///
+/// Bring everything installed up to the newest version in the repository,
+/// swapping out packages that have been obsoleted
+///
+fn upgrade( recommends : bool ) {
+    let mut state = load_state();
+    let mut c = new_context("runtime", recommends);
+    let available = new_resolver().list();
+
+    let replacements : Vec<Replacement> = match c.upgrade(&state, &available) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Could not solve the upgrade: {}", e);
+            exit(1);
+        }
+    };
+    print_warnings(&c);
+    for r in replacements.iter() {
+        println!("Replacing {} with {}", r.old, r.new);
+    }
 
     let dependencies = c.flatten("ROOT".to_string());
+    let fs = deploy(c.resolver(), &dependencies);
 
+    state.record_solve(&dependencies, &replacements, true);
+    save_state(&state);
 
+    mount(fs);
+}
+
+///
+/// Collect and extract the output of Context::flatten, and load it into a
+/// filesystem ready to be mounted. Exits if any of it fails
+///
+fn deploy( resolver : &RepoResolver, dependencies : &[(String, Version, Vec<Reason>)] ) -> MutagenFilesystem {
+
+    let repos = load_repositories();
+    let mut collector = Collector::new(open_cache());
+    match Limits::load(Path::new(CONFIG)) {
+        Ok(l) => collector.limit(l),
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    }
+    match TrustStore::load(Path::new(TRUSTED_KEYS)) {
+        Ok(t) => collector.trust(t),
+        Err(e) => {
+            println!("Could not load trusted keys: {}", e);
+            exit(1);
+        }
+    }
+    let mut progress = new_progress();
     let mut fs = MutagenFilesystem::new();
-    // We then collect the packages, extract them, and load them to the vfs
-    for (n,v) in dependencies {
-        collect_package(n.clone(), v.data.clone());
 
-        let pkg_name = format!("./root/tmp/mutagen/tmp_dl/{}-{}.tar.xz", n, v.data);
-        let pkg_dir = format!("/home/josh/devel/mutagen/root/mutagen/pkg/{}/{}/", n, v.data);
+    // Work out where everything comes from first, so the downloads can all
+    // run at once
+    let mut plan = vec!();
+    for &(ref key, ref v, ref why) in dependencies.iter() {
+        // Slotted packages come back as name:slot
+        let (n, slot) = split_key(key);
+        let n = n.to_string();
+        let slot = slot.map(|s| s.to_string());
 
-        create_dir_all(pkg_dir.clone());
+        // Everything here was resolved during the solve, so this is cached
+        let meta = match resolver.resolve(&n, &v.data) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Could not resolve {}-{}: {:?}", n, v.data, e);
+                exit(1);
+            }
+        };
 
-        extract_xz(pkg_name, Path::new(&pkg_dir));
+        // Meta-packages are only there for their deps, and have no files
+        if meta.meta_package {
+            println!("Installing {}-{} ({}, metadata only)", key, v.data, describe_reasons(why));
+            continue;
+        }
 
-        fs.inject(Path::new(&pkg_dir), Tag{
+        let repo = match repos.get(meta.repository.as_ref().map(|r| r.as_str())) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("No repository to download {}-{} from: {:?}", n, v.data, e);
+                exit(1);
+            }
+        };
+
+        println!("Installing {}-{} ({}) from {}", key, v.data, describe_reasons(why), repo.name);
+        plan.push((repo, meta, slot));
+    }
+
+    // We then collect the packages, extract them, and load them to the vfs.
+    // ^C stops the downloads, leaving what arrived to be resumed next time
+    catch_interrupt(collector.canceller());
+    let results = collector.collect_all(&plan.iter().map(|&(r, ref m, _)| (r, m)).collect::<Vec<_>>(), &mut *progress);
+    release_interrupt();
+    let mut failed = false;
+    for (&(_, ref meta, _), result) in plan.iter().zip(results.iter()) {
+        match *result {
+            // Only worth mentioning if nothing else went wrong
+            Err(CollectError::Cancelled) => {},
+            Err(ref e) => {
+                println!("Could not download {}-{}: {}", meta.name, meta.version, e);
+                failed = true;
+            },
+            Ok(_) => {},
+        }
+    }
+    if !failed && results.iter().any(|r| r.is_err()) {
+        println!("Downloads were cancelled");
+        failed = true;
+    }
+    if failed {
+        exit(1);
+    }
+
+    for ((_, meta, slot), archive) in plan.into_iter().zip(results.into_iter()) {
+        let n = meta.name;
+        let v = meta.version;
+        let pkg_name = archive.unwrap().to_string_lossy().to_string();
+        let pkg_dir = format!("/home/josh/devel/mutagen/root/mutagen/pkg/{}/{}/", n, v);
+
+        match create_dir_all(pkg_dir.clone()) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("Could not create {}: {}", pkg_dir, e);
+                exit(1);
+            }
+        }
+
+        extract_xz(pkg_name, Path::new(&pkg_dir), &format!("{}-{}", n, v), &mut *progress);
+
+        match fs.inject(Path::new(&pkg_dir), Tag{
             owner_name: n.clone(),
-            owner_version: v.data.clone(),
-        });
+            owner_version: v.clone(),
+            owner_slot: slot,
+        }) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("Could not add {}-{} to the filesystem: {:?}", n, v, e);
+                exit(1);
+            }
+        }
     }
 
+    return fs;
+}
+
+///
+/// Have ^C set cancel instead of killing us, until release_interrupt. Only
+/// the first flag given is ever set
+///
+fn catch_interrupt( cancel : Arc<AtomicBool> ) {
+    let _ = INTERRUPT.set(cancel);
+    unsafe {
+        libc::signal(libc::SIGINT, interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+fn release_interrupt() {
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+extern "C" fn interrupted( _ : libc::c_int ) {
+    match INTERRUPT.get() {
+        Some(cancel) => cancel.store(true, Ordering::SeqCst),
+        None => {},
+    }
+}
+
+fn mount( fs : MutagenFilesystem ) {
     // Launch the vfs
     let mountpoint = "./root/mutagen/vfs";
     fuse::mount(fs, &mountpoint, &[]).expect("Couldn't mount filesystem");
@@ -53,3 +771,39 @@ fn main() {
     // The last step is to overlay the vfs onto the real filesystem
     // TODO
 }
+
+fn print_warnings( c : &Context<RepoResolver> ) {
+    for w in c.warnings() {
+        println!("Warning: {}", w);
+    }
+}
+
+fn describe_reasons( why : &[Reason] ) -> String {
+    why.iter().map(|r| match *r {
+        Reason::Explicit => "explicitly requested".to_string(),
+        Reason::Dependency{ ref owner, ref kind } => format!("{} dependency of {}", kind.as_str(), owner),
+    }).collect::<Vec<String>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_selection;
+
+    #[test]
+    fn parse_selection_ranges() {
+        assert_eq!( parse_selection( "1 3-5", 5 ), Some(vec!(0, 2, 3, 4)) );
+        assert_eq!( parse_selection( "1,3-5", 5 ), Some(vec!(0, 2, 3, 4)) );
+        assert_eq!( parse_selection( " 2 , 4-4 ", 4 ), Some(vec!(1, 3)) );
+        assert_eq!( parse_selection( "", 3 ), Some(vec!()) );
+    }
+
+    #[test]
+    fn parse_selection_refuses() {
+        assert_eq!( parse_selection( "0", 3 ), None );
+        assert_eq!( parse_selection( "4", 3 ), None );
+        assert_eq!( parse_selection( "2-4", 3 ), None );
+        assert_eq!( parse_selection( "3-1", 3 ), None );
+        assert_eq!( parse_selection( "1-", 3 ), None );
+        assert_eq!( parse_selection( "a", 3 ), None );
+    }
+}
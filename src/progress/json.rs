@@ -0,0 +1,106 @@
+extern crate serde_json;
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use self::serde_json::Map;
+use self::serde_json::Value;
+
+use collector::collector::Attempt;
+use collector::collector::CollectError;
+use progress::Progress;
+
+///
+/// Writes every event as a line of JSON, for tools that drive mutagen. Each
+/// has an "event" naming the method it came from, plus that method's
+/// arguments, e.g.
+///
+///     {"event":"package","package":"foo-1.0","received":4096,"total":null,"speed":1024}
+///
+/// Output is flushed after every line so readers see events as they happen.
+///
+pub struct JsonProgress<W : Write> {
+    out : W,
+}
+
+impl<W : Write> JsonProgress<W> {
+    pub fn new( out : W ) -> JsonProgress<W> {
+        JsonProgress { out : out }
+    }
+
+    fn emit( &mut self, event : &str, fields : Vec<(&str, Value)> ) {
+        let mut line = Map::new();
+        line.insert("event".to_string(), Value::String(event.to_string()));
+        for (k, v) in fields {
+            line.insert(k.to_string(), v);
+        }
+
+        // Nobody listening isn't a reason to stop installing
+        let _ = writeln!(self.out, "{}", Value::Object(line));
+        let _ = self.out.flush();
+    }
+}
+
+fn size( n : Option<u64> ) -> Value {
+    match n {
+        Some(n) => Value::from(n),
+        None => Value::Null,
+    }
+}
+
+impl<W : Write> Progress for JsonProgress<W> {
+    fn begin( &mut self, packages : &[(String, Option<u64>)] ) {
+        let list = packages.iter().map(|&(ref p, total)| {
+            let mut m = Map::new();
+            m.insert("package".to_string(), Value::String(p.clone()));
+            m.insert("total".to_string(), size( total ));
+            Value::Object(m)
+        }).collect();
+        self.emit("begin", vec!(("packages", Value::Array(list))));
+    }
+
+    fn package( &mut self, package : &str, received : u64, total : Option<u64>, speed : u64 ) {
+        self.emit("package", vec!(("package", Value::String(package.to_string())),
+                                  ("received", Value::from(received)),
+                                  ("total", size( total )),
+                                  ("speed", Value::from(speed))));
+    }
+
+    fn overall( &mut self, received : u64, total : Option<u64>, speed : u64 ) {
+        self.emit("overall", vec!(("received", Value::from(received)),
+                                  ("total", size( total )),
+                                  ("speed", Value::from(speed))));
+    }
+
+    fn retry( &mut self, package : &str, failed : &Attempt, delay : Duration ) {
+        self.emit("retry", vec!(("package", Value::String(package.to_string())),
+                                ("url", Value::String(failed.url.clone())),
+                                ("attempt", Value::from(failed.attempt)),
+                                ("error", Value::String(failed.error.to_string())),
+                                ("delay_ms", Value::from(delay.as_secs() * 1000 + delay.subsec_millis() as u64))));
+    }
+
+    fn finished( &mut self, package : &str, result : &Result<PathBuf, CollectError> ) {
+        self.emit("finished", vec!(("package", Value::String(package.to_string())),
+                                   ("ok", Value::Bool(result.is_ok())),
+                                   ("path", match *result {
+                                       Ok(ref p) => Value::String(p.to_string_lossy().to_string()),
+                                       Err(_) => Value::Null,
+                                   }),
+                                   ("error", match *result {
+                                       Ok(_) => Value::Null,
+                                       Err(ref e) => Value::String(e.to_string()),
+                                   })));
+    }
+
+    fn end( &mut self ) {
+        self.emit("end", vec!());
+    }
+
+    fn extract( &mut self, package : &str, done : u64, total : u64 ) {
+        self.emit("extract", vec!(("package", Value::String(package.to_string())),
+                                  ("done", Value::from(done)),
+                                  ("total", Value::from(total))));
+    }
+}
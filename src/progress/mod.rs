@@ -0,0 +1,63 @@
+pub mod json;
+pub mod terminal;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use collector::collector::Attempt;
+use collector::collector::CollectError;
+
+///
+/// Receives progress as packages are downloaded and extracted, e.g. to draw
+/// a progress bar or pass it on to a provisioning tool. Packages are named
+/// <name>-<version>. Sizes are in bytes and speeds in bytes per second, and a
+/// total is None when the server didn't say and the metadata doesn't either.
+///
+/// package and overall are called at most ten times a second while
+/// downloads run. Every method does nothing by default, so implementations
+/// only need the ones they care about.
+///
+pub trait Progress {
+    /// Downloads are starting for packages, with their expected sizes
+    fn begin( &mut self, _packages : &[(String, Option<u64>)] ) {}
+
+    /// How far along the download of package is
+    fn package( &mut self, _package : &str, _received : u64, _total : Option<u64>, _speed : u64 ) {}
+
+    /// How far along the downloads are, all together
+    fn overall( &mut self, _received : u64, _total : Option<u64>, _speed : u64 ) {}
+
+    /// A try at downloading package failed, and another is coming after delay
+    fn retry( &mut self, _package : &str, _failed : &Attempt, _delay : Duration ) {}
+
+    /// The download of package is over, one way or the other
+    fn finished( &mut self, _package : &str, _result : &Result<PathBuf, CollectError> ) {}
+
+    /// Downloads are over
+    fn end( &mut self ) {}
+
+    /// How much of package's archive has been unpacked
+    fn extract( &mut self, _package : &str, _done : u64, _total : u64 ) {}
+}
+
+/// Progress that goes nowhere
+pub struct Silent;
+
+impl Progress for Silent {}
+
+/// Format a number of bytes for people, e.g. 1.5 MiB
+pub fn human_size( bytes : u64 ) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
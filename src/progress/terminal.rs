@@ -0,0 +1,112 @@
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use collector::collector::Attempt;
+use collector::collector::CollectError;
+use progress::Progress;
+use progress::human_size;
+
+const BAR_WIDTH : usize = 30;
+
+///
+/// Draws a single progress bar on stderr, redrawn in place at most ten times
+/// a second. Retries and failures are printed above it as they happen, so
+/// they aren't drawn over.
+///
+///     [###########-------------------]  37%  1.1 MiB/3.0 MiB  412.0 KiB/s  2/5
+///
+pub struct TerminalProgress {
+    // Packages finished, out of how many
+    finished  : usize,
+    packages  : usize,
+    drawn     : Option<Instant>,
+    // Length of what's on the line now, so shorter lines can blank it out
+    width     : usize,
+}
+
+impl TerminalProgress {
+    pub fn new() -> TerminalProgress {
+        TerminalProgress { finished : 0, packages : 0, drawn : None, width : 0 }
+    }
+
+    fn due( &mut self ) -> bool {
+        match self.drawn {
+            Some(t) if t.elapsed() < Duration::from_millis(100) => false,
+            _ => { self.drawn = Some(Instant::now()); true },
+        }
+    }
+
+    fn draw( &mut self, line : String ) {
+        let pad = self.width.saturating_sub( line.len() );
+        let mut err = io::stderr();
+        let _ = write!(err, "\r{}{}", line, " ".repeat( pad ));
+        let _ = err.flush();
+        self.width = line.len();
+    }
+
+    /// Print a line of its own, leaving the bar to be drawn again under it
+    fn say( &mut self, line : String ) {
+        self.clear();
+        let _ = writeln!(io::stderr(), "{}", line);
+        self.drawn = None;
+    }
+
+    fn clear( &mut self ) {
+        if self.width > 0 {
+            let _ = write!(io::stderr(), "\r{}\r", " ".repeat( self.width ));
+            self.width = 0;
+        }
+    }
+}
+
+fn bar( done : u64, total : u64 ) -> String {
+    let filled = if total == 0 { BAR_WIDTH } else { (done.min( total ) as f64 / total as f64 * BAR_WIDTH as f64) as usize };
+    format!("[{}{}] {:>3}%", "#".repeat( filled ), "-".repeat( BAR_WIDTH - filled ),
+            if total == 0 { 100 } else { done.min( total ) * 100 / total })
+}
+
+impl Progress for TerminalProgress {
+    fn begin( &mut self, packages : &[(String, Option<u64>)] ) {
+        self.finished = 0;
+        self.packages = packages.len();
+    }
+
+    fn overall( &mut self, received : u64, total : Option<u64>, speed : u64 ) {
+        let line = match total {
+            Some(t) => format!("{}  {}/{}  {}/s  {}/{}", bar( received, t ), human_size( received ), human_size( t ),
+                               human_size( speed ), self.finished, self.packages),
+            None => format!("{}  {}/s  {}/{}", human_size( received ), human_size( speed ), self.finished, self.packages),
+        };
+        self.draw( line );
+    }
+
+    fn retry( &mut self, package : &str, failed : &Attempt, delay : Duration ) {
+        self.say( format!("{}: {}, retrying in {:.1}s", package, failed.error, delay.as_secs() as f64 + delay.subsec_millis() as f64 / 1000.0) );
+    }
+
+    fn finished( &mut self, package : &str, result : &Result<PathBuf, CollectError> ) {
+        self.finished += 1;
+        match *result {
+            Ok(_) | Err(CollectError::Cancelled) => {},
+            Err(ref e) => self.say( format!("{}: {}", package, e) ),
+        }
+    }
+
+    fn end( &mut self ) {
+        self.clear();
+    }
+
+    fn extract( &mut self, package : &str, done : u64, total : u64 ) {
+        if done < total && !self.due() {
+            return;
+        }
+        let line = format!("Extracting {} {}", package, bar( done, total ));
+        self.draw( line );
+        if done >= total {
+            self.clear();
+        }
+    }
+}
@@ -1,27 +1,196 @@
 use std::collections::HashMap;
-use std::ascii::AsciiExt;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt;
 
+use solver::package_resolver::Dependency;
+use solver::package_resolver::DependencyKind;
+use solver::package_resolver::Metadata;
 use solver::package_resolver::Resolver;
+use solver::package_resolver::ResolverError;
 use solver::node::Node;
 use solver::node::Rule;
 use solver::version::Version;
+use solver::name::NameError;
+use solver::name::is_file_dep;
+use solver::name::normalize;
+use solver::name::normalize_dep;
+use solver::name::normalize_key;
+use solver::name::node_key;
+use solver::name::split_key;
+use solver::policy::Policy;
+use state::installed::InstallReason;
+use state::installed::InstalledState;
+
+/// Packages built with this arch can be installed anywhere
+pub const ANY_ARCH : &'static str = "any";
+
+#[derive(Debug)]
+pub enum SolveError {
+    /// The resolver couldn't produce metadata for name-version
+    Unresolvable { name : String, version : String, cause : ResolverError },
+    /// The version the rules settled on was built for another arch
+    ArchMismatch { name : String, version : String, arch : String, target : String },
+    /// name doesn't follow the package name grammar
+    BadName { name : String, cause : NameError },
+    /// A specific slot was asked for, but the version the rules settled on
+    /// lives in another one
+    SlotMismatch { name : String, version : String, slot : Option<String>, wanted : String },
+    /// An upgrade replaced name with by, but something still requires name
+    Obsoleted { name : String, by : String },
+    /// No single version of name satisfies every rule on it
+    Conflict { name : String, rules : Vec<String> },
+    /// owner depends on the file at path, but no package ships it
+    NoProvider { path : String, owner : String },
+    /// A group was requested, but nothing installable belongs to it
+    EmptyGroup { name : String },
+    /// A policy refused the version the rules settled on
+    Vetoed { name : String, version : String, policy : String, reason : String, rules : Vec<String> },
+}
+
+impl fmt::Display for SolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SolveError::Unresolvable{ ref name, ref version, ref cause } =>
+                write!(f, "could not resolve {}-{}: {:?}", name, version, cause),
+            SolveError::ArchMismatch{ ref name, ref version, ref arch, ref target } =>
+                write!(f, "{}-{} is built for {}, but the target is {}", name, version, arch, target),
+            SolveError::BadName{ ref name, ref cause } =>
+                write!(f, "bad package name {:?}: {}", name, cause),
+            SolveError::SlotMismatch{ ref name, ref version, ref slot, ref wanted } =>
+                write!(f, "{}-{} is in slot {}, but slot {} was required", name, version,
+                       slot.as_ref().map(|s| s.as_str()).unwrap_or("(none)"), wanted),
+            SolveError::Obsoleted{ ref name, ref by } =>
+                write!(f, "{} was replaced by {}, but is still required", name, by),
+            SolveError::Conflict{ ref name, ref rules } =>
+                write!(f, "no version of {} satisfies every requirement:\n    {}", name, rules.join("\n    ")),
+            SolveError::NoProvider{ ref path, ref owner } =>
+                write!(f, "{} needs {}, but no package provides it", owner, path),
+            SolveError::EmptyGroup{ ref name } =>
+                write!(f, "no installable package belongs to group {}", name),
+            SolveError::Vetoed{ ref name, ref version, ref policy, ref reason, ref rules } =>
+                write!(f, "{}-{} was refused by the {} policy ({}), but:\n    {}", name, version, policy, reason,
+                       rules.join("\n    ")),
+        }
+    }
+}
+
+/// Why a package ended up in the output of flatten
+#[derive(Clone, Debug)]
+pub enum Reason {
+    /// Requested directly through inject
+    Explicit,
+    /// Pulled in as a dependency of owner
+    Dependency { owner : String, kind : DependencyKind },
+}
+
+/// An installed package that an upgrade swaps for one that obsoletes it
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    pub old : String,
+    pub new : String,
+}
 
 pub struct Context<T>{
     pub map : HashMap<String, Node>,
     resolver : T,
+    arch : String,
+    // Dependencies of any other kind are ignored during the solve
+    kinds : Vec<DependencyKind>,
+    // Consulted in order before a version is accepted
+    policies : Vec<Box<dyn Policy>>,
+    // Picks which members of a group to install. Everything, if unset
+    selector : Option<Box<dyn Fn(&str, &[Metadata]) -> Vec<String>>>,
+    // Who ships each file that has been depended on so far
+    file_owners : HashMap<String, Vec<(String, String)>>,
+    // Follow recommends deps even when they aren't in kinds
+    recommends : bool,
+    // Recommendations that had to be dropped, and why
+    warnings : Vec<String>,
+    // Recommendations waiting for the required packages to settle, each
+    // with the owner and the version of it that recommended them
+    pending : VecDeque<(String, String, Dependency)>,
+    // Packages an upgrade replaced, to the node and version replacing them
+    redirects : HashMap<String, (String, String)>,
+    // Recommendations that were taken, so they can still give way to
+    // something required later
+    accepted : Vec<Accepted>,
 }
 
+/// A recommendation that was added to the solve
+#[derive(Clone)]
+struct Accepted {
+    owner   : String,
+    name    : String,
+    // Every node whose rules changed because of it
+    touched : HashSet<String>,
+}
+
+/// Groups are requested as @name, which can't be confused with a package
+pub const GROUP_PREFIX : char = '@';
+
+/// Owns the rules that keep a node below versions a policy refused. Like
+/// ROOT, it's upper case so it can't be confused with a package
+const POLICY : &'static str = "POLICY";
+
 impl<T : Resolver> Context<T>{
-    pub fn new(rs : T) -> Context<T> {
+    /// Create a context that only selects packages built for arch (or any)
+    pub fn new(rs : T, arch : &str) -> Context<T> {
         let hm : HashMap<String, Node> = HashMap::new();
-        let mut e = Context { resolver : rs, map : hm };
+        let mut e = Context { resolver : rs, map : hm, arch : arch.to_string(),
+                              kinds : vec!(DependencyKind::Runtime), policies : vec!(), selector : None,
+                              file_owners : HashMap::new(), recommends : true, warnings : vec!(),
+                              pending : VecDeque::new(), redirects : HashMap::new(),
+                              accepted : vec!() };
         e.add_node("ROOT");
+        e.add_node(POLICY);
         return e;
     }
 
-    pub fn flatten( &self, start : String ) -> Vec<(String, Version)> {
+    ///
+    /// Choose which kinds of dependencies to follow. Only runtime deps are
+    /// followed by default; a build chroot would want Runtime and Build.
+    /// This must be set before anything is injected.
+    ///
+    pub fn follow( &mut self, kinds : &[DependencyKind] ) {
+        self.kinds = kinds.to_vec();
+    }
+
+    /// Have policy vet every version before it's accepted
+    pub fn add_policy( &mut self, policy : Box<dyn Policy> ) {
+        self.policies.push(policy);
+    }
+
+    ///
+    /// Choose whether recommended packages are installed. They are by
+    /// default; minimal images may not want them.
+    ///
+    pub fn install_recommends( &mut self, on : bool ) {
+        self.recommends = on;
+    }
+
+    /// Everything the solve gave up on without failing, to show the user
+    pub fn warnings( &self ) -> &[String] {
+        &self.warnings
+    }
+
+    ///
+    /// Let selector choose which members of a group get installed. It gets
+    /// the group and the newest installable version of each member, and
+    /// returns the names it wants.
+    ///
+    pub fn select_with( &mut self, selector : Box<dyn Fn(&str, &[Metadata]) -> Vec<String>> ) {
+        self.selector = Some(selector);
+    }
+
+    /// The resolver this context solves with
+    pub fn resolver( &self ) -> &T {
+        &self.resolver
+    }
+
+    pub fn flatten( &self, start : String ) -> Vec<(String, Version, Vec<Reason>)> {
         // Start at the node identified by start and collect its
-        let mut ret : Vec<(String, Version)> = vec!();
+        let mut ret : Vec<(String, Version, Vec<Reason>)> = vec!();
         let mut deps : Vec<String> = vec!();
         match self.map.get( &start ) {
             Some(n) => {
@@ -45,7 +214,8 @@ impl<T : Resolver> Context<T>{
                 match i.next() {
                     Some(s) => {
                         let v = self.get_target_version( s.to_owned() );
-                        ret.push( (s.to_owned(),v) );
+                        let why = self.get_reasons( s );
+                        ret.push( (s.to_owned(),v,why) );
                         // For each of these, recurse and append
                         let subdeps = self.flatten( s.to_owned() );
                         ret = [ret, subdeps].concat();
@@ -55,13 +225,190 @@ impl<T : Resolver> Context<T>{
             }
         }
 
-        // Remove duplicates in the return vector
+        // Remove duplicates in the return vector. Names are canonical by the
+        // time they become nodes, so equal names are the same package
+        ret.sort_by(|a,b| a.0.cmp(&(b.0)));
+        ret.dedup_by(|a,b| a.0 == b.0);
+
+        // A package asked for both with and without a slot can settle on
+        // the same version under both nodes. It only needs installing once
+        ret.sort_by(|a,b| split_key(&a.0).0.cmp(split_key(&b.0).0)
+                              .then(a.1.data.cmp(&b.1.data))
+                              .then(a.0.cmp(&b.0)));
+        ret.dedup_by(|a,b| {
+            if split_key(&a.0).0 == split_key(&b.0).0 && a.1.data == b.1.data {
+                b.2.extend(a.2.drain(..));
+                return true;
+            }
+            false
+        });
         ret.sort_by(|a,b| a.0.cmp(&(b.0)));
-        ret.dedup_by(|a,b| a.0.eq_ignore_ascii_case(&(b.0)));
 
         return ret;
     }
 
+    ///
+    /// Find the shortest chain of owners that leads from ROOT to name, i.e.
+    /// ROOT -> vim -> ncurses. Returns None if nothing requires name.
+    ///
+    pub fn why<'a>( &self, name : &'a str ) -> Option<Vec<String>> {
+        let name = match normalize_key( name ) {
+            Ok(n) => n,
+            Err(_) => return None,
+        };
+
+        // Breadth first search down from ROOT. deps are kept in the order
+        // they were added, so ties always break the same way
+        let mut parents : HashMap<String, String> = HashMap::new();
+        let mut seen : HashSet<String> = HashSet::new();
+        let mut queue : VecDeque<String> = VecDeque::new();
+        seen.insert("ROOT".to_string());
+        queue.push_back("ROOT".to_string());
+
+        while let Some(current) = queue.pop_front() {
+            if current == name {
+                // Walk the parents back up to ROOT
+                let mut chain : Vec<String> = vec!(current.clone());
+                let mut cursor = current;
+                while let Some(p) = parents.get(&cursor) {
+                    chain.push(p.clone());
+                    cursor = p.clone();
+                }
+                chain.reverse();
+                return Some(chain);
+            }
+
+            match self.map.get( &current ) {
+                Some(n) => {
+                    for d in n.deps.iter() {
+                        if seen.insert(d.clone()) {
+                            parents.insert(d.clone(), current.clone());
+                            queue.push_back(d.clone());
+                        }
+                    }
+                },
+                None => panic!("Node could not be resolved"),
+            }
+        }
+
+        return None;
+    }
+
+    ///
+    /// Every node that holds a rule on name, i.e. everything that depends on
+    /// it directly
+    ///
+    pub fn dependents<'a>( &self, name : &'a str ) -> Vec<String> {
+        let name = match normalize_key( name ) {
+            Ok(n) => n,
+            Err(_) => return vec!(),
+        };
+
+        let mut ret : Vec<String> = match self.map.get( &name ) {
+            Some(n) => n.rules.iter().filter(|r| r.owner != POLICY).map(|r| r.owner.clone()).collect(),
+            None => vec!(),
+        };
+
+        ret.sort();
+        ret.dedup();
+        return ret;
+    }
+
+    ///
+    /// Solve for upgrading everything in installed to the newest version in
+    /// available. Explicitly installed packages are injected at their newest
+    /// version, and everything else comes back in as dependencies. If some
+    /// available package obsoletes an installed one, it takes its place,
+    /// including as a dependency of anything that still names the old one.
+    /// The replacements made are returned so the install record can carry
+    /// reasons over.
+    ///
+    pub fn upgrade( &mut self, installed : &InstalledState, available : &[Metadata] ) -> Result<Vec<Replacement>, SolveError> {
+        let mut replacements : Vec<Replacement> = vec!();
+        let mut wanted : Vec<(String, String)> = vec!();
+
+        // Every replacement has to be known before anything is injected, so
+        // that no dependency gets to pull the old package back in
+        for p in installed.packages.values() {
+            let (pkg, slot) = split_key( &p.name );
+
+            match self.newest_obsoleting( pkg, available ) {
+                Some(m) => {
+                    // Keep the replacement in a slot if the original was
+                    let new = node_key( &m.name, slot.and(m.slot.as_ref().map(|s| s.as_str())) );
+                    if p.reason == InstallReason::Explicit {
+                        wanted.push( (new.clone(), m.version.clone()) );
+                    }
+                    self.redirects.insert( pkg.to_string(), (new.clone(), m.version.clone()) );
+                    replacements.push(Replacement{ old : p.name.clone(), new : new });
+                },
+                None if p.reason == InstallReason::Explicit => {
+                    // If the repository dropped it, stay where we are
+                    let version = match self.newest( pkg, slot, available ) {
+                        Some(m) => m.version.clone(),
+                        None => p.version.clone(),
+                    };
+                    wanted.push( (p.name.clone(), version) );
+                },
+                None => {},
+            }
+        }
+
+        for (name, version) in wanted {
+            self.inject( name, version )?;
+        }
+
+        // Deps are redirected, so only a direct request can get here, and
+        // installing both would just collide
+        for r in replacements.iter() {
+            if self.why( &r.old ).is_some() {
+                return Err(SolveError::Obsoleted{ name : r.old.clone(), by : r.new.clone() });
+            }
+        }
+
+        Ok(replacements)
+    }
+
+    fn installable( &self, m : &Metadata ) -> bool {
+        m.arch == ANY_ARCH || m.arch == self.arch
+    }
+
+    /// The newest version of name we could install, in slot if given
+    fn newest<'a, 'b>( &self, name : &'a str, slot : Option<&'a str>, available : &'b [Metadata] ) -> Option<&'b Metadata> {
+        let mut ret : Option<&Metadata> = None;
+        for m in available.iter() {
+            if m.name != name || !self.installable( m ) {
+                continue;
+            }
+            if slot.is_some() && m.slot.as_ref().map(|s| s.as_str()) != slot {
+                continue;
+            }
+            ret = match ret {
+                Some(r) if Version::new(&r.version).cmp(&Version::new(&m.version)) >= 0 => Some(r),
+                _ => Some(m),
+            };
+        }
+        return ret;
+    }
+
+    /// The newest version of anything that obsoletes name. Ties between
+    /// different packages go to the first by name, so the choice is stable.
+    fn newest_obsoleting<'a, 'b>( &self, name : &'a str, available : &'b [Metadata] ) -> Option<&'b Metadata> {
+        let mut candidates : Vec<&Metadata> = available.iter()
+            .filter(|m| m.name != name && self.installable( m ) && m.obsoletes.iter().any(|o| o == name))
+            .collect();
+        candidates.sort_by(|a,b| a.name.cmp(&b.name));
+
+        let mut ret : Option<&Metadata> = None;
+        for m in candidates {
+            ret = match ret {
+                Some(r) if r.name != m.name || Version::new(&r.version).cmp(&Version::new(&m.version)) >= 0 => Some(r),
+                _ => Some(m),
+            };
+        }
+        return ret;
+    }
+
     fn get_target_version( &self, name : String ) -> Version {
         // Convert the name to a node
         match self.map.get( &name ){
@@ -74,21 +421,91 @@ impl<T : Resolver> Context<T>{
         }
     }
 
-    pub fn inject( &mut self, name : String, version : String ){
-        // Check if package exists
+    fn get_reasons<'a>( &self, name : &'a str ) -> Vec<Reason> {
+        match self.map.get( name ){
+            Some( n ) => {
+                return n.rules.iter().filter(|r| r.owner != POLICY).map(|r| {
+                    if r.owner == "ROOT" {
+                        Reason::Explicit
+                    } else {
+                        Reason::Dependency{ owner : r.owner.clone(), kind : r.kind }
+                    }
+                }).collect();
+            },
+            None => {
+                panic!("Node could not be resolved");
+            },
+        }
+    }
+
+    ///
+    /// Request a specific version of a package, optionally in a slot, as in
+    /// python:3. A group, as in @base, is expanded into requests for each
+    /// member at up to its newest version, and version is ignored. If this
+    /// fails, the context is left half solved and should be thrown away.
+    ///
+    pub fn inject( &mut self, name : String, version : String ) -> Result<(), SolveError> {
+        if name.starts_with(GROUP_PREFIX) {
+            return self.inject_group( &name[GROUP_PREFIX.len_utf8()..] );
+        }
+
+        self.request( name, &version, &version )
+    }
+
+    /// Add an explicit rule on name allowing any version from min to max
+    fn request<'a>( &mut self, name : String, min : &'a str, max : &'a str ) -> Result<(), SolveError> {
+        let name = normalize_key( &name ).map_err(|e| SolveError::BadName{ name : name.clone(), cause : e })?;
+
+        let rule = Rule{ owner: "ROOT".to_string(), min_version: Version::new(min), max_version: Version::new(max), kind: DependencyKind::Runtime, weak: false };
+        self.add_constraint( name, rule )?;
+
+        // Only now that everything required has a version can we tell
+        // which recommendations fit
+        self.add_recommends();
+        Ok(())
+    }
+
+    fn inject_group<'a>( &mut self, group : &'a str ) -> Result<(), SolveError> {
+        let group = normalize( group ).map_err(|e| SolveError::BadName{ name : group.to_string(), cause : e })?;
+        let available = self.resolver.group( &group );
+
+        let mut names : Vec<&str> = available.iter().map(|m| m.name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        let members : Vec<Metadata> = names.iter()
+            .filter_map(|n| self.newest( n, None, &available ))
+            .cloned()
+            .collect();
+        if members.len() == 0 {
+            return Err(SolveError::EmptyGroup{ name : group });
+        }
+
+        let chosen : Vec<String> = match self.selector {
+            Some(ref select) => select( &group, &members ),
+            None => members.iter().map(|m| m.name.clone()).collect(),
+        };
+        // The newest of each is only a ceiling, so members that need an
+        // older version of one another can still have it
+        for m in members.into_iter().filter(|m| chosen.contains(&m.name)) {
+            self.request( m.name, "0", &m.version )?;
+        }
 
-        // Add an explicit rule requiring THIS version
-        // of the package
-        let rule = Rule{ owner: "ROOT".to_string(), min_version: Version::new(&version), max_version: Version::new(&version) };
-        self.add_constraint( name, rule );
+        Ok(())
     }
 
-    fn add_constraint(&mut self, name : String, new_rule : Rule){
+    fn add_constraint(&mut self, name : String, new_rule : Rule) -> Result<(), SolveError> {
         // Test if this package exists in the map already
         // I KNOW this can be simplified TODO
         let start_v : Version;
         let node_found : bool;
         match self.map.get( &name ) {
+            // A node can lose all of its rules when the package that
+            // required it changes version. Its deps are stale by then, so
+            // treat it like a brand new node
+            Some(n) if n.rules.len() == 0 => {
+                start_v = new_rule.max_version.to_owned();
+                node_found = false;
+            },
             Some(n) => {
                 start_v = n.collapse_rules().max_version;
                 node_found = true;
@@ -101,12 +518,55 @@ impl<T : Resolver> Context<T>{
         };
 
         // If the node does not exist, create it
-        if !node_found {
+        if !self.map.contains_key( &name ) {
             self.add_node( &name );
         }
 
+        // Whatever a policy refused before may be out of range now, and a
+        // version it was kept below may no longer be allowed, so that's
+        // worked out again if the version changes
+        if new_rule.owner != POLICY {
+            self.remove_rule( POLICY, &name );
+        }
+
         // Insert the new rule
-        self.add_rule( &new_rule.owner, &name, &new_rule.min_version.data, &new_rule.max_version.data );
+        self.add_rule( &new_rule.owner, &name, &new_rule.min_version.data, &new_rule.max_version.data, new_rule.kind, new_rule.weak );
+
+        // Recommendations give way to anything that conflicts with them
+        let dropped : Vec<String> = match self.map.get( &name ) {
+            Some(n) if !n.satisfiable() && !new_rule.weak => {
+                n.rules.iter().filter(|r| r.weak).map(|r| r.owner.clone()).collect()
+            },
+            _ => vec!(),
+        };
+        for owner in dropped.iter() {
+            self.warnings.push(format!("dropped {}'s recommendation of {}, which conflicts with other requirements", owner, name));
+            self.remove_rule( owner, &name );
+            self.accepted.retain(|a| a.owner != *owner || a.name != name);
+        }
+
+        // So do recommendations that only reached this node through what
+        // they pulled in, newest first, until the conflict is gone
+        if !new_rule.weak {
+            let mut i = self.accepted.len();
+            while i > 0 && self.map.get( &name ).map(|n| !n.satisfiable()).unwrap_or(false) {
+                i -= 1;
+                if !self.accepted[i].touched.contains( &name ) {
+                    continue;
+                }
+                let a = self.accepted.remove( i );
+                self.warnings.push(format!("dropped {}'s recommendation of {}, which conflicts with other requirements on {}", a.owner, a.name, name));
+                self.retract( &a.owner, &a.name )?;
+            }
+        }
+
+        // Bail out with everyone's demands rather than settling on nothing
+        match self.map.get( &name ) {
+            Some(n) if !n.satisfiable() => {
+                return Err(SolveError::Conflict{ name : name.clone(), rules : n.explain() });
+            },
+            _ => {},
+        }
 
         // If the target version of the package changed, we need to
         // refresh all of our rules for this node
@@ -121,15 +581,16 @@ impl<T : Resolver> Context<T>{
         };
 
         if start_v.cmp(&end_v) != 0 || !node_found{
-            self.refresh_node( &name );
+            return self.refresh_node( &name );
         }
 
+        Ok(())
     }
 
     ///
     /// Clean out old deps and resbuild them from the metadata
     ///
-    fn refresh_node( &mut self, name : &str ){
+    fn refresh_node( &mut self, name : &str ) -> Result<(), SolveError> {
         // Figure out this node's version
         let target_v : String;
         match self.map.get( name ) {
@@ -141,8 +602,57 @@ impl<T : Resolver> Context<T>{
             }
         };
 
-        match self.resolver.resolve( name, &target_v ){
+        // Slotted nodes are keyed name:slot, but the resolver only knows names
+        let (pkg, wanted_slot) = split_key( name );
+
+        match self.resolver.resolve( pkg, &target_v ){
             Ok(meta) => {
+                // We only know what a version was built for once we have its
+                // metadata, so this is the earliest we can turn it down
+                if meta.arch != ANY_ARCH && meta.arch != self.arch {
+                    return Err(SolveError::ArchMismatch{
+                        name : pkg.to_string(),
+                        version : target_v,
+                        arch : meta.arch,
+                        target : self.arch.clone(),
+                    });
+                }
+
+                match (wanted_slot, meta.slot.as_ref()) {
+                    (Some(w), slot) if slot.map(|s| s.as_str()) != Some(w) => {
+                        return Err(SolveError::SlotMismatch{
+                            name : pkg.to_string(),
+                            version : target_v,
+                            slot : meta.slot,
+                            wanted : w.to_string(),
+                        });
+                    },
+                    // Only one version per slot can be selected, so a request
+                    // that didn't name the slot has to share its node
+                    (None, Some(slot)) => {
+                        return self.fold_into_slot( name, &node_key( pkg, Some(slot) ) );
+                    },
+                    _ => {},
+                }
+
+                // A refused version gives way to the newest one below it
+                // that the rules allow and no policy minds
+                match self.veto( &meta ) {
+                    Some((policy, reason)) => {
+                        return match self.fallback( name, &meta ) {
+                            Some(v) => self.cap( name, &v ),
+                            None => Err(SolveError::Vetoed{
+                                name : pkg.to_string(),
+                                version : target_v,
+                                policy : policy,
+                                reason : reason,
+                                rules : self.map.get( name ).map(|n| n.explain()).unwrap_or(vec!()),
+                            }),
+                        };
+                    },
+                    None => {},
+                }
+
                 // Extract list of deps
                 let deps : Vec<String>;
                 match self.map.get( name ) {
@@ -166,30 +676,321 @@ impl<T : Resolver> Context<T>{
                 }
 
 
+                // Let the resolver start on everything we're about to ask
+                // for, so it isn't fetched one at a time below
+                let wanted : Vec<(String, String)> = meta.deps.iter()
+                    .filter(|r| self.follows(r.kind) && !is_file_dep(&r.name))
+                    .map(|r| (r.name.clone(), r.max_version.clone()))
+                    .collect();
+                self.resolver.prefetch( &wanted );
+
                 // Using the deps from the resolver, add rules originating
                 // from this now
                 let mut new_deps_iter = meta.deps.iter();
                 loop {
                     match new_deps_iter.next() {
+                        // Skip the kinds of deps we weren't asked to follow
+                        Some(r) if !self.follows(r.kind) => {},
+                        // Recommendations wait until the required packages
+                        // have been added, so they can't get in their way
+                        Some(r) if r.kind == DependencyKind::Recommends => {
+                            self.pending.push_back( (name.to_string(), target_v.clone(), r.clone()) );
+                        },
                         Some(r) => {
                             // Force the target node to re-evaluate its life
-                            let new_rule = Rule{ owner : name.to_string(), min_version : Version::new(&(r.min_version)), max_version : Version::new(&(r.max_version)) };
-                            self.add_constraint( r.name.to_string(), new_rule );
+                            let (key, new_rule) = self.dep_rule( name, r )?;
+                            self.add_constraint( key, new_rule )?;
                         },
                         None => break,
                     }
                 }
 
+                Ok(())
             },
-            Err(_) => panic!("Could not resolve package")
+            Err(e) => Err(SolveError::Unresolvable{
+                name : pkg.to_string(),
+                version : target_v,
+                cause : e,
+            })
         }
     }
 
     // Private helper functions
     ///////////////////////////////
 
+    ///
+    /// Move every rule on name over to key, the node for the slot its
+    /// version turned out to live in, leaving name with nothing on it
+    ///
+    fn fold_into_slot<'a>( &mut self, name : &'a str, key : &'a str ) -> Result<(), SolveError> {
+        let (deps, rules) = match self.map.get( name ) {
+            Some(n) => (n.deps.clone(), n.rules.clone()),
+            None => panic!("Request on non-existant node requested"),
+        };
+
+        // Anything it pulled in was for a version it may not end up with
+        for d in deps.iter() {
+            self.remove_rule( name, d );
+        }
+        // A policy's cap goes along with the last of the others
+        for r in rules.into_iter().filter(|r| r.owner != POLICY) {
+            self.remove_rule( &r.owner, name );
+            self.add_constraint( key.to_string(), r )?;
+        }
+
+        Ok(())
+    }
+
+    /// The first policy to refuse meta, and why
+    fn veto( &self, meta : &Metadata ) -> Option<(String, String)> {
+        for p in self.policies.iter() {
+            match p.check( meta ) {
+                Ok(_) => {},
+                Err(reason) => return Some((p.name().to_string(), reason)),
+            }
+        }
+        None
+    }
+
+    ///
+    /// The newest version of name older than meta that its rules allow, and
+    /// that's installable here in the same slot without a policy refusing it
+    ///
+    fn fallback<'a>( &self, name : &'a str, meta : &Metadata ) -> Option<String> {
+        let (pkg, _) = split_key( name );
+        let min = self.map.get( name )?.collapse_rules().min_version;
+        let refused = Version::new( &meta.version );
+
+        let mut best : Option<Version> = None;
+        for v in self.resolver.versions( pkg ).iter().map(|v| Version::new( v )) {
+            let in_range = v.try_cmp( &refused ) == Some(-1) && v.try_cmp( &min ).map(|c| c >= 0).unwrap_or(false);
+            let newer = match best {
+                Some(ref b) => v.try_cmp( b ) == Some(1),
+                None => true,
+            };
+            if !in_range || !newer {
+                continue;
+            }
+
+            match self.resolver.resolve( pkg, &v.data ) {
+                Ok(ref m) if self.installable( m ) && m.slot == meta.slot && self.veto( m ).is_none() => best = Some(v),
+                _ => {},
+            }
+        }
+        best.map(|v| v.data)
+    }
+
+    /// Keep name at version or below, on behalf of the policies
+    fn cap<'a>( &mut self, name : &'a str, version : &'a str ) -> Result<(), SolveError> {
+        let min = match self.map.get( name ) {
+            Some(n) => n.collapse_rules().min_version.data,
+            None => panic!("Request on non-existant node requested"),
+        };
+        self.remove_rule( POLICY, name );
+        self.add_rule( POLICY, name, &min, version, DependencyKind::Runtime, false );
+        self.refresh_node( name )
+    }
+
+    fn follows( &self, kind : DependencyKind ) -> bool {
+        self.kinds.contains(&kind) || (kind == DependencyKind::Recommends && self.recommends)
+    }
+
+    /// The node key a dep of owner points at, and the rule it puts there
+    fn dep_rule<'a>( &mut self, owner : &'a str, r : &Dependency ) -> Result<(String, Rule), SolveError> {
+        let weak = r.kind == DependencyKind::Recommends;
+        if is_file_dep(&r.name) {
+            let path = normalize_dep( &r.name ).map_err(|e| SolveError::BadName{ name : r.name.clone(), cause : e })?;
+            let (provider, min, max) = self.file_provider( &path )
+                .ok_or(SolveError::NoProvider{ path : path.clone(), owner : owner.to_string() })?;
+            return Ok((provider, Rule{ owner : owner.to_string(), min_version : min, max_version : max, kind : r.kind, weak : weak }));
+        }
+
+        let new_rule = Rule{ owner : owner.to_string(), min_version : Version::new(&(r.min_version)), max_version : Version::new(&(r.max_version)), kind : r.kind, weak : weak };
+        // Resolvers should already hand us canonical names, but the node
+        // keys depend on it
+        let dep_name = normalize( &r.name ).map_err(|e| SolveError::BadName{ name : r.name.clone(), cause : e })?;
+
+        // The replacement's versions have nothing to do with the range asked
+        // of the package it replaced, so any of them will do
+        match self.redirects.get( &dep_name ) {
+            Some(&(ref key, ref version)) => {
+                return Ok((key.clone(), Rule{ owner : owner.to_string(), min_version : Version::new("0"), max_version : Version::new(version), kind : r.kind, weak : weak }));
+            },
+            None => {},
+        }
+
+        let dep_slot = match r.slot {
+            Some(ref s) => Some(normalize( s ).map_err(|e| SolveError::BadName{ name : s.clone(), cause : e })?),
+            None => None,
+        };
+        Ok((node_key( &dep_name, dep_slot.as_ref().map(|s| s.as_str()) ), new_rule))
+    }
+
+    ///
+    /// Add every pending recommendation that fits, dropping the rest. This
+    /// can queue up more, which are handled in turn.
+    ///
+    fn add_recommends( &mut self ) {
+        while let Some((owner, version, dep)) = self.pending.pop_front() {
+            // The owner may have moved to a version that doesn't recommend
+            // this, or been dropped altogether
+            let current = match self.map.get( &owner ) {
+                Some(n) if n.rules.len() > 0 => n.collapse_rules().max_version.data,
+                _ => continue,
+            };
+            if current != version {
+                continue;
+            }
+
+            match self.dep_rule( &owner, &dep ) {
+                Ok((key, new_rule)) => self.add_weak_constraint( key, new_rule ),
+                Err(e) => self.warnings.push(format!("dropped {}'s recommendation of {}: {}", owner, dep.name, e)),
+            }
+        }
+    }
+
+    ///
+    /// Add a rule that may be dropped. If anything along the way fails, e.g.
+    /// the recommended package has a conflicting dep of its own, the whole
+    /// attempt is rolled back and the solve carries on without it.
+    ///
+    fn add_weak_constraint( &mut self, name : String, new_rule : Rule ) {
+        let owner = new_rule.owner.clone();
+        let before = self.map.clone();
+        let accepted = self.accepted.clone();
+        let warned = self.warnings.len();
+        let queued = self.pending.len();
+        match self.add_constraint( name.clone(), new_rule ) {
+            Ok(_) => {
+                let touched : HashSet<String> = self.map.iter()
+                    .filter(|&(k, n)| before.get( k ).map(|b| rule_summary( b ) != rule_summary( n )).unwrap_or(true))
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                self.accepted.push(Accepted{ owner : owner, name : name, touched : touched });
+            },
+            Err(e) => {
+                self.map = before;
+                self.accepted = accepted;
+                self.warnings.truncate( warned );
+                self.pending.truncate( queued );
+                self.warnings.push(format!("dropped {}'s recommendation of {}: {}", owner, name, e));
+            }
+        }
+    }
+
+    ///
+    /// Remove owner's rules on target, along with everything target was only
+    /// in the solve to provide. Whatever is left settles on a new version.
+    ///
+    fn retract<'a>( &mut self, owner : &'a str, target : &'a str ) -> Result<(), SolveError> {
+        let start_v = self.map.get( target ).filter(|n| n.rules.len() > 0 && n.satisfiable()).map(|n| n.collapse_rules().max_version);
+        self.remove_rule( owner, target );
+
+        let (deps, rules_left) = match self.map.get( target ) {
+            Some(n) => (n.deps.clone(), n.rules.len()),
+            None => panic!("Request on non-existant node requested"),
+        };
+        if rules_left == 0 {
+            // Nothing wants it any more, so nothing it wanted counts either
+            for d in deps.iter() {
+                self.retract( target, d )?;
+            }
+            return Ok(());
+        }
+
+        // A conflict still there is for whoever is adding the rule to sort out
+        match (start_v, self.map.get( target )) {
+            (Some(v), Some(n)) if n.satisfiable() && n.collapse_rules().max_version.cmp( &v ) != 0 => self.refresh_node( target ),
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// Pick the package that satisfies a dep on path, along with a range of
+    /// its versions that ship it. A package that's already part of the solve
+    /// wins, so /bin/sh doesn't drag in a second shell; otherwise the first
+    /// provider by name does, so the choice is stable.
+    ///
+    /// The range only covers versions we could install, and never spans one
+    /// that lacks the file. Of the runs that qualify, the one overlapping
+    /// what the provider is already held to wins, then the newest.
+    ///
+    fn file_provider<'a>( &mut self, path : &'a str ) -> Option<(String, Version, Version)> {
+        if !self.file_owners.contains_key( path ) {
+            let owners = self.resolver.file_owners( path );
+            self.file_owners.insert( path.to_string(), owners );
+        }
+        let owners = self.file_owners[path].clone();
+
+        let chosen = owners.iter()
+            .find(|o| self.map.get( &o.0 ).map(|n| n.rules.len() > 0).unwrap_or(false))
+            .or(owners.first())?
+            .0.clone();
+
+        let mut eligible : Vec<Version> = owners.iter()
+            .filter(|o| o.0 == chosen)
+            .filter(|o| self.resolver.resolve( &o.0, &o.1 ).map(|m| self.installable( &m )).unwrap_or(false))
+            .map(|o| Version::new( &o.1 ))
+            .collect();
+        let listed = self.resolver.versions( &chosen );
+
+        // Without the full list we can't tell what lies between two owners,
+        // so only the newest is safe
+        if listed.is_empty() {
+            let mut newest : Option<Version> = None;
+            for e in eligible.into_iter() {
+                match newest {
+                    Some(ref n) if e.try_cmp( n ) != Some(1) => continue,
+                    _ => {},
+                }
+                newest = Some(e);
+            }
+            eligible = newest.into_iter().collect();
+        }
+        let others : Vec<Version> = listed.iter()
+            .filter(|v| !eligible.iter().any(|e| e.data == **v))
+            .map(|v| Version::new( v ))
+            .collect();
+
+        // Versions sharing the same nearest gaps on both sides form a run
+        let mut runs : Vec<(Option<String>, Option<String>, Version, Version)> = vec!();
+        for e in eligible.iter() {
+            let below = closest( &others, e, -1 );
+            let above = closest( &others, e, 1 );
+            match runs.iter_mut().find(|r| r.0 == below && r.1 == above) {
+                Some(r) => {
+                    if e.try_cmp( &r.2 ) == Some(-1) { r.2 = e.clone(); }
+                    if e.try_cmp( &r.3 ) == Some(1) { r.3 = e.clone(); }
+                    continue;
+                },
+                None => {},
+            }
+            runs.push((below, above, e.clone(), e.clone()));
+        }
+
+        let held = match self.map.get( &chosen ) {
+            Some(n) if n.rules.len() > 0 => Some(n.collapse_rules()),
+            _ => None,
+        };
+        let overlaps = |r : &(Option<String>, Option<String>, Version, Version)| match held {
+            Some(ref h) => r.2.try_cmp( &h.max_version ).map(|c| c <= 0).unwrap_or(false) &&
+                           r.3.try_cmp( &h.min_version ).map(|c| c >= 0).unwrap_or(false),
+            None => false,
+        };
+        let mut best : Option<&(Option<String>, Option<String>, Version, Version)> = None;
+        for r in runs.iter() {
+            best = match best {
+                Some(b) if overlaps( b ) && !overlaps( r ) => Some(b),
+                Some(b) if overlaps( b ) == overlaps( r ) && r.3.try_cmp( &b.3 ) != Some(1) => Some(b),
+                _ => Some(r),
+            };
+        }
+
+        best.map(|r| (chosen.clone(), r.2.clone(), r.3.clone()))
+    }
+
     // TODO use entry API here
-    fn add_rule<'a>( &mut self, from : &'a str, to : &'a str, min : &'a str, max : &'a str ) {
+    fn add_rule<'a>( &mut self, from : &'a str, to : &'a str, min : &'a str, max : &'a str, kind : DependencyKind, weak : bool ) {
         // Ensure that both from and to exist
         if !self.map.contains_key(from) || !self.map.contains_key(to){
             panic!("Attempted to add a bad rule");
@@ -198,7 +999,9 @@ impl<T : Resolver> Context<T>{
         // Create new rule
         let new_rule : Rule = Rule{ min_version : Version::new(min),
                                     max_version : Version::new(max),
-                                    owner       : from.to_string().clone()
+                                    owner       : from.to_string().clone(),
+                                    kind        : kind,
+                                    weak        : weak
                                   };
 
         // The panic!'s should never occur, but if they do, we should
@@ -238,6 +1041,12 @@ impl<T : Resolver> Context<T>{
             Some(n) => n.rules.retain(|i| i.owner != owner),
             None => panic!("Bad thing")
         };
+
+        // A policy's cap means nothing once nobody else wants the package
+        let orphaned = self.map.get(target).map(|n| n.rules.len() > 0 && n.rules.iter().all(|r| r.owner == POLICY)).unwrap_or(false);
+        if orphaned {
+            self.remove_rule( POLICY, target );
+        }
     }
 
     ///
@@ -251,3 +1060,19 @@ impl<T : Resolver> Context<T>{
     }
 }
 
+/// A node's rules, in a form that can be compared
+fn rule_summary( n : &Node ) -> Vec<(String, String, String, bool)> {
+    n.rules.iter().map(|r| (r.owner.clone(), r.min_version.data.clone(), r.max_version.data.clone(), r.weak)).collect()
+}
+
+/// The nearest of versions on the given side of v (-1 below, 1 above)
+fn closest( versions : &[Version], v : &Version, side : i32 ) -> Option<String> {
+    let mut ret : Option<&Version> = None;
+    for o in versions.iter().filter(|o| o.try_cmp( v ) == Some(side)) {
+        match ret {
+            Some(r) if o.try_cmp( r ) != Some(-side) => {},
+            _ => ret = Some(o),
+        }
+    }
+    ret.map(|r| r.data.clone())
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::fs::remove_file;
+use std::hash::Hasher;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use solver::package_resolver::Dependency;
+use solver::package_resolver::DependencyKind;
+use solver::package_resolver::Metadata;
+use solver::package_resolver::Resolver;
+use solver::package_resolver::ResolverError;
+
+/// Bump this whenever the record layout changes, so old caches get dropped
+const FORMAT : u32 = 7;
+
+///
+/// Keeps parsed metadata on disk between runs. There is one cache file per
+/// repository revision, metadata-<format>-<scope>-<hash of revision>.bin,
+/// and any file left over from an older revision is deleted as soon as the
+/// repository changes. The scope keeps resolvers over different
+/// repositories from deleting each other's files when they share a dir.
+///
+/// The file is a log of records, each a little endian u32 length followed by
+/// the record itself. New entries are appended as they're resolved, so a run
+/// that dies part way through only loses its last record.
+///
+pub struct DiskCacheResolver<R> {
+    inner : R,
+    entries : Mutex<HashMap<(String, String), Metadata>>,
+    // None when the repository has no revision, in which case we can't know
+    // when to throw the cache away and don't keep one at all
+    log : Mutex<Option<File>>,
+}
+
+impl<R : Resolver> DiskCacheResolver<R> {
+    pub fn new<'a>( inner : R, cache_dir : &Path, scope : &'a str ) -> DiskCacheResolver<R> {
+        let mut entries : HashMap<(String, String), Metadata> = HashMap::new();
+        let mut log : Option<File> = None;
+
+        match inner.revision() {
+            Some(rev) => {
+                // Revisions come from the repository, so they only go into
+                // the file name hashed
+                let mut hasher = DefaultHasher::new();
+                hasher.write( rev.as_bytes() );
+                let scope = scope_name( scope );
+                let file_name = format!("metadata-{}-{}-{:016x}.bin", FORMAT, scope, hasher.finish());
+                let _ = create_dir_all( cache_dir );
+                remove_stale( cache_dir, &scope, &file_name );
+
+                let path = cache_dir.join( &file_name );
+                let mut good : u64 = 0;
+                match File::open( &path ) {
+                    Ok(mut f) => {
+                        let mut data : Vec<u8> = vec!();
+                        if f.read_to_end( &mut data ).is_ok() {
+                            good = load_records( &data, &mut entries ) as u64;
+                        }
+                    },
+                    Err(_) => {},
+                }
+
+                // Anything after the last good record is a write that was
+                // cut off, and would hide every record appended after it
+                log = OpenOptions::new().create(true).append(true).open( &path ).ok()
+                    .and_then(|f| f.set_len( good ).ok().map(|_| f));
+            },
+            None => {},
+        }
+
+        DiskCacheResolver {
+            inner : inner,
+            entries : Mutex::new(entries),
+            log : Mutex::new(log),
+        }
+    }
+}
+
+impl<R : Resolver> Resolver for DiskCacheResolver<R> {
+    fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError> {
+        let key = (name.to_string(), version.to_string());
+        match self.entries.lock().unwrap().get(&key) {
+            Some(m) => return Ok(m.clone()),
+            None => {},
+        }
+
+        let meta = self.inner.resolve( name, version )?;
+
+        // Failing to write the cache only costs us speed next time
+        match *self.log.lock().unwrap() {
+            Some(ref mut f) => {
+                let mut record : Vec<u8> = vec!();
+                write_str( &mut record, name );
+                write_str( &mut record, version );
+                write_metadata( &mut record, &meta );
+
+                let mut framed : Vec<u8> = vec!();
+                write_u32( &mut framed, record.len() as u32 );
+                framed.extend_from_slice( &record );
+                let _ = f.write_all( &framed );
+            },
+            None => {},
+        }
+
+        self.entries.lock().unwrap().insert( key, meta.clone() );
+        Ok(meta)
+    }
+
+    fn prefetch( &self, wanted : &[(String, String)] ) {
+        self.inner.prefetch( wanted )
+    }
+
+    fn revision( &self ) -> Option<String> {
+        self.inner.revision()
+    }
+
+    fn group<'a>( &self, group : &'a str ) -> Vec<Metadata> {
+        self.inner.group( group )
+    }
+
+    fn versions<'a>( &self, name : &'a str ) -> Vec<String> {
+        self.inner.versions( name )
+    }
+
+    fn file_owners<'a>( &self, path : &'a str ) -> Vec<(String, String)> {
+        self.inner.file_owners( path )
+    }
+}
+
+// Helper functions
+///////////////////////////////
+
+/// Scopes go into file names between dashes, so they can't have any
+fn scope_name<'a>( scope : &'a str ) -> String {
+    scope.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+///
+/// Delete scope's cache files, of any format, for every revision other than
+/// the current one
+///
+fn remove_stale<'a>( cache_dir : &Path, scope : &'a str, current : &'a str ) {
+    match read_dir( cache_dir ) {
+        Ok(entries) => {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with("metadata-") || !name.ends_with(".bin") || name == current {
+                    continue;
+                }
+                // metadata-<format>-<scope>-...
+                let ours = name["metadata-".len()..].splitn(2, '-').nth(1)
+                               .map(|rest| rest.starts_with( &format!("{}-", scope) )).unwrap_or(false);
+                if ours {
+                    let _ = remove_file( entry.path() );
+                }
+            }
+        },
+        Err(_) => {},
+    }
+}
+
+///
+/// Load every good record in data into entries. Returns how many bytes of
+/// data they take up.
+///
+fn load_records( data : &[u8], entries : &mut HashMap<(String, String), Metadata> ) -> usize {
+    let mut r = Reader{ data : data, pos : 0 };
+    let mut good = 0;
+    loop {
+        // A short or garbled record means the last write was cut off, and
+        // everything before it is still good
+        let len = match r.u32() {
+            Some(l) => l as usize,
+            None => break,
+        };
+        let record = match r.bytes(len) {
+            Some(b) => b,
+            None => break,
+        };
+
+        let mut rr = Reader{ data : record, pos : 0 };
+        match read_record( &mut rr ) {
+            Some((key, meta)) => { entries.insert(key, meta); },
+            None => break,
+        }
+        good = r.pos;
+    }
+    good
+}
+
+fn read_record( r : &mut Reader ) -> Option<((String, String), Metadata)> {
+    let key = (r.str()?, r.str()?);
+
+    let name = r.str()?;
+    let version = r.str()?;
+    let mut deps : Vec<Dependency> = vec!();
+    for _ in 0..r.u32()? {
+        deps.push(Dependency{
+            name : r.str()?,
+            min_version : r.str()?,
+            max_version : r.str()?,
+            kind : DependencyKind::from_str( &r.str()? )?,
+            slot : r.opt_str()?,
+        });
+    }
+
+    let mut m = Metadata::new( name, version, deps );
+    m.description = r.opt_str()?;
+    m.license = r.opt_str()?;
+    m.homepage = r.opt_str()?;
+    m.maintainer = r.opt_str()?;
+    m.arch = r.str()?;
+    m.slot = r.opt_str()?;
+    m.installed_size = r.opt_u64()?;
+    m.download_size = r.opt_u64()?;
+    m.checksum = r.opt_str()?;
+    m.build_date = r.opt_str()?;
+    for _ in 0..r.u32()? {
+        m.groups.push(r.str()?);
+    }
+    for _ in 0..r.u32()? {
+        m.obsoletes.push(r.str()?);
+    }
+    m.meta_package = r.u8()? == 1;
+    for _ in 0..r.u32()? {
+        m.files.push(r.str()?);
+    }
+    m.repository = r.opt_str()?;
+
+    Some((key, m))
+}
+
+fn write_metadata( out : &mut Vec<u8>, m : &Metadata ) {
+    write_str( out, &m.name );
+    write_str( out, &m.version );
+    write_u32( out, m.deps.len() as u32 );
+    for d in m.deps.iter() {
+        write_str( out, &d.name );
+        write_str( out, &d.min_version );
+        write_str( out, &d.max_version );
+        write_str( out, d.kind.as_str() );
+        write_opt_str( out, &d.slot );
+    }
+
+    write_opt_str( out, &m.description );
+    write_opt_str( out, &m.license );
+    write_opt_str( out, &m.homepage );
+    write_opt_str( out, &m.maintainer );
+    write_str( out, &m.arch );
+    write_opt_str( out, &m.slot );
+    write_opt_u64( out, m.installed_size );
+    write_opt_u64( out, m.download_size );
+    write_opt_str( out, &m.checksum );
+    write_opt_str( out, &m.build_date );
+    write_u32( out, m.groups.len() as u32 );
+    for g in m.groups.iter() {
+        write_str( out, g );
+    }
+    write_u32( out, m.obsoletes.len() as u32 );
+    for o in m.obsoletes.iter() {
+        write_str( out, o );
+    }
+    out.push( m.meta_package as u8 );
+    write_u32( out, m.files.len() as u32 );
+    for f in m.files.iter() {
+        write_str( out, f );
+    }
+    write_opt_str( out, &m.repository );
+}
+
+fn write_u32( out : &mut Vec<u8>, v : u32 ) {
+    out.extend_from_slice( &v.to_le_bytes() );
+}
+
+fn write_str<'a>( out : &mut Vec<u8>, s : &'a str ) {
+    write_u32( out, s.len() as u32 );
+    out.extend_from_slice( s.as_bytes() );
+}
+
+fn write_opt_str( out : &mut Vec<u8>, s : &Option<String> ) {
+    match *s {
+        Some(ref s) => { out.push(1); write_str( out, s ); },
+        None => out.push(0),
+    }
+}
+
+fn write_opt_u64( out : &mut Vec<u8>, v : Option<u64> ) {
+    match v {
+        Some(v) => { out.push(1); out.extend_from_slice( &v.to_le_bytes() ); },
+        None => out.push(0),
+    }
+}
+
+struct Reader<'a> {
+    data : &'a [u8],
+    pos  : usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes( &mut self, len : usize ) -> Option<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return None;
+        }
+        let ret = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Some(ret)
+    }
+
+    fn u8( &mut self ) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u32( &mut self ) -> Option<u32> {
+        let b = self.bytes(4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64( &mut self ) -> Option<u64> {
+        let b = self.bytes(8)?;
+        Some(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    fn str( &mut self ) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8( self.bytes(len)?.to_vec() ).ok()
+    }
+
+    // These return Some(None) for a field that was written as missing, and
+    // None when the data itself is bad
+    fn opt_str( &mut self ) -> Option<Option<String>> {
+        match self.u8()? {
+            0 => Some(None),
+            1 => Some(Some(self.str()?)),
+            _ => None,
+        }
+    }
+
+    fn opt_u64( &mut self ) -> Option<Option<u64>> {
+        match self.u8()? {
+            0 => Some(None),
+            1 => Some(Some(self.u64()?)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    /// Resolves anything, counting how often it had to
+    struct Counting {
+        calls : AtomicUsize,
+    }
+
+    impl Resolver for Counting {
+        fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError> {
+            self.calls.fetch_add( 1, Ordering::SeqCst );
+            Ok(Metadata::new( name.to_string(), version.to_string(), vec!() ))
+        }
+
+        fn revision( &self ) -> Option<String> {
+            Some("1".to_string())
+        }
+    }
+
+    fn open( dir : &Path ) -> DiskCacheResolver<Counting> {
+        DiskCacheResolver::new( Counting{ calls : AtomicUsize::new(0) }, dir, "test" )
+    }
+
+    fn calls( r : &DiskCacheResolver<Counting> ) -> usize {
+        r.inner.calls.load( Ordering::SeqCst )
+    }
+
+    #[test]
+    fn truncated_log() {
+        let dir = temp_dir().join( format!("mutagen-disk-cache-{}", process::id()) );
+        let _ = remove_dir_all( &dir );
+
+        let r = open( &dir );
+        r.resolve( "vim", "7.4" ).unwrap();
+        r.resolve( "ncurses", "6.0" ).unwrap();
+        drop( r );
+
+        // Cut the last record short, as a run killed mid-write would
+        let path = read_dir( &dir ).unwrap().next().unwrap().unwrap().path();
+        let len = path.metadata().unwrap().len();
+        OpenOptions::new().write(true).open( &path ).unwrap().set_len( len - 3 ).unwrap();
+
+        let r = open( &dir );
+        assert_eq!( r.resolve( "vim", "7.4" ).unwrap().version, "7.4" );
+        assert_eq!( calls( &r ), 0 );
+        r.resolve( "ncurses", "6.0" ).unwrap();
+        assert_eq!( calls( &r ), 1 );
+        drop( r );
+
+        // The cut off record went, so the one written again is readable
+        let r = open( &dir );
+        r.resolve( "vim", "7.4" ).unwrap();
+        r.resolve( "ncurses", "6.0" ).unwrap();
+        assert_eq!( calls( &r ), 0 );
+
+        let _ = remove_dir_all( &dir );
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use solver::node::Node;
+
+extern crate serde_json;
+use self::serde_json::Map;
+use self::serde_json::Value;
+
+///
+/// Render a solved graph (Context.map) as Graphviz DOT. Every edge goes from
+/// the owner of a rule to the package it constrains, labelled with the range
+/// that was requested and the version that was chosen.
+///
+pub fn to_dot( map : &HashMap<String, Node> ) -> String {
+    let mut out = String::new();
+    out.push_str("digraph mutagen {\n");
+
+    let names = live_nodes( map );
+    for name in names.iter() {
+        let label = match chosen_version( map, name ) {
+            Some(v) => format!("{}\\n{}", escape(name), escape(&v)),
+            None => escape(name),
+        };
+        out.push_str(&format!("    \"{}\" [label=\"{}\"];\n", escape(name), label));
+    }
+
+    for name in names.iter() {
+        let n = &map[name];
+        let chosen = chosen_version( map, name ).unwrap_or(String::new());
+        for r in n.rules.iter().filter(|r| names.contains(&r.owner)) {
+            out.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}..{} ({}) = {}\"];\n",
+                                  escape(&r.owner), escape(name),
+                                  escape(&r.min_version.data), escape(&r.max_version.data),
+                                  r.kind.as_str(), escape(&chosen)));
+        }
+    }
+
+    out.push_str("}\n");
+    return out;
+}
+
+///
+/// Render a solved graph as JSON, with the same information as to_dot:
+/// { "nodes" : [ { "name", "version" } ],
+///   "edges" : [ { "from", "to", "min_version", "max_version", "kind", "chosen" } ] }
+///
+pub fn to_json( map : &HashMap<String, Node> ) -> String {
+    let mut nodes : Vec<Value> = vec!();
+    let mut edges : Vec<Value> = vec!();
+
+    let names = live_nodes( map );
+    for name in names.iter() {
+        let chosen = chosen_version( map, name );
+
+        let mut node = Map::new();
+        node.insert("name".to_string(), Value::String(name.to_string()));
+        node.insert("version".to_string(), match chosen {
+            Some(ref v) => Value::String(v.to_string()),
+            None => Value::Null,
+        });
+        nodes.push(Value::Object(node));
+
+        for r in map[name].rules.iter().filter(|r| names.contains(&r.owner)) {
+            let mut edge = Map::new();
+            edge.insert("from".to_string(), Value::String(r.owner.clone()));
+            edge.insert("to".to_string(), Value::String(name.to_string()));
+            edge.insert("min_version".to_string(), Value::String(r.min_version.data.clone()));
+            edge.insert("max_version".to_string(), Value::String(r.max_version.data.clone()));
+            edge.insert("kind".to_string(), Value::String(r.kind.as_str().to_string()));
+            edge.insert("chosen".to_string(), match chosen {
+                Some(ref v) => Value::String(v.to_string()),
+                None => Value::Null,
+            });
+            edges.push(Value::Object(edge));
+        }
+    }
+
+    let mut graph = Map::new();
+    graph.insert("nodes".to_string(), Value::Array(nodes));
+    graph.insert("edges".to_string(), Value::Array(edges));
+
+    serde_json::to_string_pretty(&Value::Object(graph)).unwrap()
+}
+
+// Helper functions
+///////////////////////////////
+
+/// Names of every node reachable from ROOT, sorted so that output is stable.
+/// Nodes that were dropped during the solve stay in the map, but they're not
+/// part of the answer.
+fn live_nodes( map : &HashMap<String, Node> ) -> Vec<String> {
+    let mut seen : HashSet<String> = HashSet::new();
+    let mut stack : Vec<String> = vec!("ROOT".to_string());
+    while let Some(current) = stack.pop() {
+        if !seen.insert(current.clone()) {
+            continue;
+        }
+        match map.get( &current ) {
+            Some(n) => stack.extend(n.deps.iter().cloned()),
+            None => panic!("Node could not be resolved"),
+        }
+    }
+
+    let mut ret : Vec<String> = seen.into_iter().collect();
+    ret.sort();
+    return ret;
+}
+
+fn chosen_version<'a>( map : &HashMap<String, Node>, name : &'a str ) -> Option<String> {
+    match map.get( name ) {
+        Some(n) if n.rules.len() > 0 => Some(n.collapse_rules().max_version.data),
+        _ => None,
+    }
+}
+
+fn escape<'a>( s : &'a str ) -> String {
+    s.replace("\\", "\\\\").replace("\"", "\\\"")
+}
@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use solver::package_resolver::Metadata;
+
+///
+/// Maps the files packages ship to the packages that ship them, so a
+/// dependency on /bin/sh can be turned into one on a package. Built from the
+/// file lists in the repository index.
+///
+pub struct FileIndex {
+    owners : HashMap<String, Vec<(String, String)>>,
+}
+
+impl FileIndex {
+    pub fn new() -> FileIndex {
+        FileIndex { owners : HashMap::new() }
+    }
+
+    pub fn from_packages( packages : &[Metadata] ) -> FileIndex {
+        let mut ret = FileIndex::new();
+        for m in packages.iter() {
+            ret.add( m );
+        }
+        return ret;
+    }
+
+    pub fn add( &mut self, meta : &Metadata ) {
+        for f in meta.files.iter() {
+            self.owners.entry( f.clone() ).or_insert(vec!())
+                .push( (meta.name.clone(), meta.version.clone()) );
+        }
+    }
+
+    /// Every (name, version) that ships path, sorted by name
+    pub fn owners<'a>( &self, path : &'a str ) -> Vec<(String, String)> {
+        let mut ret = self.owners.get( path ).cloned().unwrap_or(vec!());
+        ret.sort();
+        ret.dedup();
+        return ret;
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs::File;
+use std::fs::read_dir;
+use std::io::Read;
+use std::path::Path;
+
+use solver::package_resolver::Dependency;
+use solver::package_resolver::DependencyKind;
+use solver::package_resolver::Metadata;
+use solver::package_resolver::Resolver;
+use solver::package_resolver::ResolverError;
+use solver::version::Version;
+use solver::name::normalize;
+
+extern crate serde_json;
+use self::serde_json::Value;
+
+///
+/// Reads the JSON manifests used by the Go prototype (weave). Manifests live
+/// in a single directory as <name>-<version>.weave, with <name>-latest.weave
+/// standing in for the newest release. weave had no notion of build deps, so
+/// every dependency is treated as a runtime one.
+///
+pub struct WeaveResolver {
+    pub manifest_dir : String,
+}
+
+impl WeaveResolver {
+    fn read_manifest<'a>( &self, name : &'a str, version : &'a str ) -> Result<Value, ResolverError> {
+        // weave used ^ to mean "whatever is newest"
+        let version = if version == "^" { "latest" } else { version };
+        let name = normalize( name ).map_err(ResolverError::BadName)?;
+        let filename = format!("{}/{}-{}.weave", self.manifest_dir, name, version);
+        read_json( Path::new(&filename) )
+    }
+
+    /// Figure out which concrete version the latest manifest points at, so
+    /// that the solver never has to reason about ^
+    fn latest_version<'a>( &self, name : &'a str ) -> Result<String, ResolverError> {
+        let value = self.read_manifest( name, "latest" )?;
+        let target = value.get("Target").ok_or(ResolverError::BadSyntax)?;
+        json_str( target, "Version" )
+    }
+
+    /// Every (name, version) with a manifest, sorted. The latest manifests
+    /// name a concrete version, so they don't add anything of their own
+    pub fn packages( &self ) -> Result<Vec<(String, String)>, ResolverError> {
+        let mut ret : Vec<(String, String)> = vec!();
+        for entry in read_dir( &self.manifest_dir ).map_err(|_| ResolverError::NoFile)? {
+            let path = entry.map_err(|_| ResolverError::NoFile)?.path();
+            match path.extension() {
+                Some(e) if e == "weave" => {},
+                _ => continue,
+            }
+
+            // The file name is <name>-<version>, and both can contain
+            // dashes, so the manifest has to say which is which
+            let value = read_json( &path )?;
+            let target = value.get("Target").ok_or(ResolverError::BadSyntax)?;
+            ret.push( (json_name( target, "Name" )?, json_str( target, "Version" )?) );
+        }
+
+        ret.sort();
+        ret.dedup();
+        Ok(ret)
+    }
+}
+
+impl Resolver for WeaveResolver {
+    fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError> {
+        let value = self.read_manifest( name, version )?;
+
+        // Header
+        let target = value.get("Target").ok_or(ResolverError::BadSyntax)?;
+        let name = json_name( target, "Name" )?;
+        let version = json_str( target, "Version" )?;
+
+        // Read dependencies. weave writes null rather than an empty list
+        let mut dep_vector : Vec<Dependency> = vec!();
+        match value.get("Require") {
+            Some(&Value::Array(ref reqs)) => {
+                for r in reqs.iter() {
+                    let dep_name = json_name( r, "Name" )?;
+
+                    // _ is weave's "no lower bound"
+                    let mut min = json_str( r, "MinVersion" )?;
+                    if min == "_" {
+                        min = "0".to_string();
+                    }
+
+                    let mut max = json_str( r, "MaxVersion" )?;
+                    if max == "^" {
+                        max = self.latest_version( &dep_name )?;
+                    }
+
+                    dep_vector.push(Dependency{
+                        name : dep_name,
+                        min_version : min,
+                        max_version : max,
+                        kind : DependencyKind::Runtime,
+                        slot : None
+                    });
+                }
+            },
+            Some(&Value::Null) | None => {},
+            Some(_) => return Err(ResolverError::BadSyntax),
+        }
+
+        Ok(Metadata::new( name, version, dep_vector ))
+    }
+}
+
+///
+/// Reads venom's repository layout, where every package version gets its own
+/// directory holding a MANIFEST.json: <repo>/<name>/<version>/MANIFEST.json
+///
+pub struct VenomResolver {
+    pub repo_dir : String,
+}
+
+impl VenomResolver {
+    /// venom allowed a dependency to be a bare package name, meaning any
+    /// version will do. We pin the upper bound to the newest one on disk.
+    fn latest_version<'a>( &self, name : &'a str ) -> Result<String, ResolverError> {
+        let name = normalize( name ).map_err(ResolverError::BadName)?;
+        let pkg_dir = format!("{}/{}", self.repo_dir, name);
+        let entries = read_dir( &pkg_dir ).map_err(|_| ResolverError::NoFile)?;
+
+        let mut latest : Option<Version> = None;
+        for entry in entries {
+            let entry = entry.map_err(|_| ResolverError::NoFile)?;
+            if !entry.path().join("MANIFEST.json").is_file() {
+                continue;
+            }
+
+            let v = Version::new( &entry.file_name().to_string_lossy() );
+            latest = match latest {
+                Some(l) => if v.cmp(&l) == 1 { Some(v) } else { Some(l) },
+                None => Some(v),
+            };
+        }
+
+        match latest {
+            Some(v) => Ok(v.data),
+            None => Err(ResolverError::NoFile),
+        }
+    }
+
+    /// Every (name, version) with a manifest, sorted
+    pub fn packages( &self ) -> Result<Vec<(String, String)>, ResolverError> {
+        let mut ret : Vec<(String, String)> = vec!();
+        for pkg in read_dir( &self.repo_dir ).map_err(|_| ResolverError::NoFile)? {
+            let pkg = pkg.map_err(|_| ResolverError::NoFile)?;
+            if !pkg.path().is_dir() {
+                continue;
+            }
+            for version in read_dir( pkg.path() ).map_err(|_| ResolverError::NoFile)? {
+                let version = version.map_err(|_| ResolverError::NoFile)?;
+                if version.path().join("MANIFEST.json").is_file() {
+                    ret.push( (pkg.file_name().to_string_lossy().to_string(),
+                               version.file_name().to_string_lossy().to_string()) );
+                }
+            }
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+}
+
+impl Resolver for VenomResolver {
+    fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError> {
+        let name = normalize( name ).map_err(ResolverError::BadName)?;
+        let filename = format!("{}/{}/{}/MANIFEST.json", self.repo_dir, name, version);
+        let value = read_json( Path::new(&filename) )?;
+
+        // Header
+        let name = json_name( &value, "name" )?;
+        let version = json_str( &value, "version" )?;
+
+        // Read dependencies, which are either tables or plain names
+        let mut dep_vector : Vec<Dependency> = vec!();
+        match value.get("depends") {
+            Some(&Value::Array(ref deps)) => {
+                for d in deps.iter() {
+                    let dep = match *d {
+                        Value::String(ref dep_name) => Dependency{
+                            name : normalize( dep_name ).map_err(ResolverError::BadName)?,
+                            min_version : "0".to_string(),
+                            max_version : self.latest_version( dep_name )?,
+                            kind : DependencyKind::Runtime,
+                            slot : None
+                        },
+                        Value::Object(_) => Dependency{
+                            name : json_name( d, "name" )?,
+                            min_version : json_str( d, "minversion" )?,
+                            max_version : json_str( d, "maxversion" )?,
+                            kind : DependencyKind::Runtime,
+                            slot : None
+                        },
+                        _ => return Err(ResolverError::BadSyntax),
+                    };
+                    dep_vector.push(dep);
+                }
+            },
+            Some(&Value::Null) | None => {},
+            Some(_) => return Err(ResolverError::BadSyntax),
+        }
+
+        Ok(Metadata::new( name, version, dep_vector ))
+    }
+}
+
+// Helper functions
+///////////////////////////////
+
+fn read_json( path : &Path ) -> Result<Value, ResolverError> {
+    let mut data = String::new();
+    let mut f = File::open( path ).map_err(|_| ResolverError::NoFile)?;
+    f.read_to_string( &mut data ).map_err(|_| ResolverError::NoFile)?;
+
+    serde_json::from_str( data.as_str() ).map_err(|_| ResolverError::BadSyntax)
+}
+
+/// A package name, in canonical form
+fn json_name<'a>( value : &Value, key : &'a str ) -> Result<String, ResolverError> {
+    normalize( &json_str( value, key )? ).map_err(ResolverError::BadName)
+}
+
+fn json_str<'a>( value : &Value, key : &'a str ) -> Result<String, ResolverError> {
+    match value.get(key) {
+        Some(&Value::String(ref s)) => Ok(s.to_string()),
+        _ => Err(ResolverError::BadSyntax),
+    }
+}
@@ -2,3 +2,10 @@ pub mod context;
 pub mod node;
 pub mod version;
 pub mod package_resolver;
+pub mod legacy_resolver;
+pub mod export;
+pub mod prefetch_resolver;
+pub mod disk_cache;
+pub mod name;
+pub mod policy;
+pub mod file_index;
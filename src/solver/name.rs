@@ -0,0 +1,159 @@
+use std::fmt;
+
+/// Longest package name we accept
+pub const MAX_NAME_LEN : usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameError {
+    Empty,
+    TooLong,
+    /// Names have to start with a letter or a digit
+    BadStart(char),
+    /// Only letters, digits and @ . _ + - are allowed
+    BadChar(char),
+    /// File dependencies have to be absolute, without . or .. in them
+    BadPath,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NameError::Empty => write!(f, "package name is empty"),
+            NameError::TooLong => write!(f, "package name is longer than {} characters", MAX_NAME_LEN),
+            NameError::BadStart(c) => write!(f, "package name can't start with '{}'", c),
+            NameError::BadChar(c) => write!(f, "package name can't contain '{}'", c),
+            NameError::BadPath => write!(f, "file dependencies must be absolute paths without . or .."),
+        }
+    }
+}
+
+///
+/// Check a package name against the grammar and return its canonical form.
+///
+///     name := [a-z0-9] [a-z0-9@._+-]*
+///
+/// Upper case ASCII letters are accepted and folded to lower case, so Vim and
+/// vim are the same package. The canonical form is what every node key, file
+/// path and filesystem tag uses.
+///
+pub fn normalize<'a>( name : &'a str ) -> Result<String, NameError> {
+    if name.len() == 0 {
+        return Err(NameError::Empty);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(NameError::TooLong);
+    }
+
+    let canonical = name.to_ascii_lowercase();
+    let mut chars = canonical.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() || c.is_ascii_digit() => {},
+        Some(c) => return Err(NameError::BadStart(c)),
+        None => return Err(NameError::Empty),
+    }
+
+    for c in chars {
+        match c {
+            'a'..='z' | '0'..='9' | '@' | '.' | '_' | '+' | '-' => {},
+            _ => return Err(NameError::BadChar(c)),
+        }
+    }
+
+    Ok(canonical)
+}
+
+///
+/// The key of the node a package is selected under. Packages requested in a
+/// specific slot get a node of their own, name:slot, so that each slot can
+/// settle on a different version. ':' can't appear in a name, so the two
+/// never get confused.
+///
+pub fn node_key<'a>( name : &'a str, slot : Option<&'a str> ) -> String {
+    match slot {
+        Some(s) => format!("{}:{}", name, s),
+        None => name.to_string(),
+    }
+}
+
+/// Undo node_key, giving back the package name and the slot, if any
+pub fn split_key<'a>( key : &'a str ) -> (&'a str, Option<&'a str>) {
+    match key.find(':') {
+        Some(i) => (&key[..i], Some(&key[i + 1..])),
+        None => (key, None),
+    }
+}
+
+///
+/// Canonicalize a name that may carry a slot, as in python:3. Slots follow
+/// the same grammar as names.
+///
+pub fn normalize_key<'a>( key : &'a str ) -> Result<String, NameError> {
+    let (name, slot) = split_key( key );
+    let name = normalize( name )?;
+    match slot {
+        Some(s) => Ok(node_key( &name, Some(&normalize( s )?) )),
+        None => Ok(name),
+    }
+}
+
+///
+/// Dependencies can name a file instead of a package, as in /bin/sh, and are
+/// then satisfied by whichever package ships it
+///
+pub fn is_file_dep<'a>( name : &'a str ) -> bool {
+    name.starts_with('/')
+}
+
+///
+/// Canonicalize the target of a dependency, which is either a package name
+/// or an absolute path. Paths are only cleaned of repeated and trailing
+/// slashes, since the file lists they're looked up in are case sensitive.
+///
+pub fn normalize_dep<'a>( name : &'a str ) -> Result<String, NameError> {
+    if !is_file_dep( name ) {
+        return normalize( name );
+    }
+
+    let mut canonical = String::new();
+    for part in name.split('/').filter(|p| p.len() > 0) {
+        if part == "." || part == ".." {
+            return Err(NameError::BadPath);
+        }
+        canonical.push('/');
+        canonical.push_str( part );
+    }
+    if canonical.len() == 0 {
+        return Err(NameError::BadPath);
+    }
+
+    Ok(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_length() {
+        let longest : String = ::std::iter::repeat('a').take( MAX_NAME_LEN ).collect();
+        assert_eq!( normalize( &longest ), Ok(longest.clone()) );
+        assert_eq!( normalize( &format!("{}a", longest) ), Err(NameError::TooLong) );
+        assert_eq!( normalize( "" ), Err(NameError::Empty) );
+    }
+
+    #[test]
+    fn normalize_folds_case() {
+        assert_eq!( normalize( "Vim" ), Ok("vim".to_string()) );
+        assert_eq!( normalize( "LIBSTDC++" ), Ok("libstdc++".to_string()) );
+        assert_eq!( normalize( "python3.6" ), Ok("python3.6".to_string()) );
+    }
+
+    #[test]
+    fn normalize_start() {
+        assert_eq!( normalize( "0ad" ), Ok("0ad".to_string()) );
+        assert_eq!( normalize( "-vim" ), Err(NameError::BadStart('-')) );
+        assert_eq!( normalize( ".vim" ), Err(NameError::BadStart('.')) );
+        assert_eq!( normalize( "@vim" ), Err(NameError::BadStart('@')) );
+        assert_eq!( normalize( "vim/x" ), Err(NameError::BadChar('/')) );
+    }
+}
@@ -1,21 +1,60 @@
 use solver::version::Version;
+use solver::package_resolver::DependencyKind;
 
+#[derive(Clone)]
 pub struct Node {
     pub name : String,
     pub rules : Vec<Rule>,
     pub deps : Vec<String>,
 }
 
+#[derive(Clone)]
 pub struct Rule{
     pub min_version : Version,
     pub max_version : Version,
     pub owner       : String,
+    pub kind        : DependencyKind,
+    // Weak rules are dropped instead of failing the solve when they
+    // conflict with the others
+    pub weak        : bool,
 }
 
 impl Node{
+    /// True if a single version could satisfy every rule at once
+    pub fn satisfiable(&self) -> bool {
+        let (highest_min, lowest_max) = self.bounds();
+        highest_min.cmp( lowest_max ) <= 0
+    }
+
+    /// One line per rule, saying who wants what, for error messages
+    pub fn explain(&self) -> Vec<String> {
+        self.rules.iter().map(|r| format!("{} {} {}..{}", r.owner, if r.weak { "recommends" } else { "requires" },
+                                          r.min_version.data, r.max_version.data)).collect()
+    }
+
     /// Iterate over all the max versions of the rules to find
     /// the lowest
     pub fn collapse_rules(&self) -> Rule {
+        let (highest_min, lowest_max) = self.bounds();
+
+        assert!( highest_min.cmp( lowest_max ) == -1 ||
+                 highest_min.cmp( lowest_max ) == 0
+               );
+
+
+        let max = Version{ data : lowest_max.data.clone() };
+        let min = Version{ data : highest_min.data.clone() };
+
+        return Rule{ max_version: max,
+                     min_version: min,
+                     owner: "nobody".to_string(),
+                     kind: DependencyKind::Runtime,
+                     weak: false
+                   };
+    }
+
+    /// The highest min_version and the lowest max_version over all rules
+    fn bounds(&self) -> (&Version, &Version) {
         // We better have rule to collapse
         assert!(self.rules.len() > 0);
 
@@ -45,17 +84,6 @@ impl Node{
             }
         }
 
-        assert!( highest_min.cmp( lowest_max ) == -1 ||
-                 highest_min.cmp( lowest_max ) == 0
-               );
-
-
-        let max = Version{ data : lowest_max.data.clone() };
-        let min = Version{ data : highest_min.data.clone() };
-
-        return Rule{ max_version: max,
-                     min_version: min,
-                     owner: "nobody".to_string()
-                   };
+        return (highest_min, lowest_max);
     }
 }
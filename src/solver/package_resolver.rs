@@ -1,64 +1,486 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
+use std::fs::read_dir;
+use std::hash::Hasher;
 use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+use solver::context::ANY_ARCH;
+use solver::file_index::FileIndex;
+use solver::name::NameError;
+use solver::name::is_file_dep;
+use solver::name::normalize;
+use solver::name::normalize_dep;
 
 extern crate toml;
 
-// TODO use this
+#[derive(Debug)]
 pub enum ResolverError {
-//    NoFile,
-//    BadSyntax,
+    NoFile,
+    BadSyntax,
+    BadName(NameError),
 }
 
+#[derive(Clone)]
 pub struct Metadata {
     pub name    : String,
     pub version : String,
-    pub deps    : Vec<Dependency>
+    pub deps    : Vec<Dependency>,
+
+    // Everything below is descriptive, and is optional in the package TOML
+    pub description    : Option<String>,
+    pub license        : Option<String>,
+    pub homepage       : Option<String>,
+    pub maintainer     : Option<String>,
+    // The arch this package was built for, or "any"
+    pub arch           : String,
+    // Versions in different slots can be installed side by side
+    pub slot           : Option<String>,
+    // Sizes are in bytes
+    pub installed_size : Option<u64>,
+    pub download_size  : Option<u64>,
+    // Checksum of the package archive, as written in the TOML
+    pub checksum       : Option<String>,
+    pub build_date     : Option<String>,
+    pub groups         : Vec<String>,
+    // Names of packages this one replaces, e.g. after an upstream rename
+    pub obsoletes      : Vec<String>,
+    // Meta-packages only pull in their deps, and have no archive to fetch
+    pub meta_package   : bool,
+    // Absolute paths of the files the package ships, for file deps
+    pub files          : Vec<String>,
+    // The repository to download the package from, or None for the first
+    // configured one
+    pub repository     : Option<String>,
+}
+
+impl Metadata {
+    /// Metadata with only the fields the solver needs filled in
+    pub fn new( name : String, version : String, deps : Vec<Dependency> ) -> Metadata {
+        Metadata {
+            name           : name,
+            version        : version,
+            deps           : deps,
+            description    : None,
+            license        : None,
+            homepage       : None,
+            maintainer     : None,
+            arch           : ANY_ARCH.to_string(),
+            slot           : None,
+            installed_size : None,
+            download_size  : None,
+            checksum       : None,
+            build_date     : None,
+            groups         : vec!(),
+            obsoletes      : vec!(),
+            meta_package   : false,
+            files          : vec!(),
+            repository     : None,
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Dependency {
     pub name        : String,
     pub min_version : String,
-    pub max_version : String
+    pub max_version : String,
+    pub kind        : DependencyKind,
+    // Only versions in this slot will do, if set
+    pub slot        : Option<String>
+}
+
+/// What a dependency is needed for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DependencyKind {
+    /// Needed to run the package. This is the default
+    Runtime,
+    /// Only needed to build the package from source
+    Build,
+    /// Only needed to run the package's test suite
+    Check,
+    /// Adds functionality, but the package works without it
+    Optional,
+    /// Installed along with the package, unless it would cause a conflict
+    Recommends,
+}
+
+impl DependencyKind {
+    pub fn from_str<'a>( s : &'a str ) -> Option<DependencyKind> {
+        match s {
+            "runtime"    => Some(DependencyKind::Runtime),
+            "build"      => Some(DependencyKind::Build),
+            "check"      => Some(DependencyKind::Check),
+            "optional"   => Some(DependencyKind::Optional),
+            "recommends" => Some(DependencyKind::Recommends),
+            _            => None,
+        }
+    }
+
+    pub fn as_str( &self ) -> &'static str {
+        match *self {
+            DependencyKind::Runtime    => "runtime",
+            DependencyKind::Build      => "build",
+            DependencyKind::Check      => "check",
+            DependencyKind::Optional   => "optional",
+            DependencyKind::Recommends => "recommends",
+        }
+    }
 }
 
 pub trait Resolver {
     fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError>;
+
+    /// A hint that each (name, version) is likely to be resolved soon.
+    /// Resolvers that can fetch in the background should start doing so.
+    fn prefetch( &self, _wanted : &[(String, String)] ) {}
+
+    /// Something that changes whenever the repository behind this resolver
+    /// does, or None if that can't be told cheaply
+    fn revision( &self ) -> Option<String> { None }
+
+    /// Every version of every package that belongs to group, for
+    /// resolvers that can list their repository
+    fn group<'a>( &self, _group : &'a str ) -> Vec<Metadata> { vec!() }
+
+    /// Every version of name, for resolvers that can list their repository
+    fn versions<'a>( &self, _name : &'a str ) -> Vec<String> { vec!() }
+
+    /// Every (name, version) that ships the file at path, for resolvers
+    /// whose repository has file lists
+    fn file_owners<'a>( &self, _path : &'a str ) -> Vec<(String, String)> { vec!() }
+}
+
+///
+/// Reads package metadata from <name>-<version>.toml files in a list of
+/// dirs, e.g. one per synced repository. A package in more than one is
+/// taken from the first.
+///
+pub struct FilesystemResolver {
+    dirs  : Vec<PathBuf>,
+    // Built from every package the first time a file dep comes up
+    files : OnceLock<FileIndex>,
+}
+
+impl FilesystemResolver {
+    pub fn new( dirs : Vec<PathBuf> ) -> FilesystemResolver {
+        FilesystemResolver { dirs : dirs, files : OnceLock::new() }
+    }
+
+    ///
+    /// Read the metadata of every package in the repository, for things
+    /// like search that need to look at all of them
+    ///
+    pub fn list( &self ) -> Vec<Metadata> {
+        let mut ret : Vec<Metadata> = vec!();
+        for dir in self.dirs.iter() {
+            let entries = match read_dir(dir) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                match path.extension() {
+                    Some(e) if e == "toml" => {},
+                    _ => continue,
+                }
+
+                // The file name is <name>-<version>, and both can contain
+                // dashes, so we need the metadata itself to split them. A
+                // broken file only costs us that one package
+                match read_toml( &path.to_string_lossy() ) {
+                    Ok(ref meta) if ret.iter().any(|m| m.name == meta.name && m.version == meta.version) => continue,
+                    Ok(meta) => ret.push(meta),
+                    Err(_) => continue,
+                }
+            }
+        }
+
+        ret.sort_by(|a,b| a.name.cmp(&b.name));
+        return ret;
+    }
 }
 
-pub struct FilesystemResolver {}
 impl Resolver for FilesystemResolver{
     fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError>{
-        let filename = format!("pkg/{}-{}.toml", name, version);
-
-        let mut data = String::new();
-        let mut f = File::open(filename).expect("Unable to open file");
-        f.read_to_string(&mut data).expect("Unable to read string");
-
-        // Unpack TOML data
-        let value = toml::Parser::new(data.as_str()).parse().unwrap();
-
-        // Extract data
-        // Header
-        let meta = value["metadata"].as_table().unwrap();
-
-        // Rust is hard
-        let version = format!("{}", meta["version"]);
-        let name = format!("{}", meta["name"]);
-
-        // Read dependencies
-        let deps = value["depends"].as_table().unwrap();
-        let mut dep_vector : Vec<Dependency> = vec!();
-        for (_, val) in deps.iter() {
-            let contents = val.as_table().unwrap();
-            let d = Dependency{
-                name : contents["name"].as_str().unwrap().to_string(),
-                min_version : contents["minversion"].as_str().unwrap().to_string(),
-                max_version : contents["maxversion"].as_str().unwrap().to_string()
-            };
-            dep_vector.push(d);
+        let name = normalize( name ).map_err(ResolverError::BadName)?;
+        for dir in self.dirs.iter() {
+            let path = dir.join(format!("{}-{}.toml", name, version));
+            if path.exists() {
+                return read_toml( &path.to_string_lossy() );
+            }
+        }
+        Err(ResolverError::NoFile)
+    }
+
+    ///
+    /// The revisions of the dirs that exist, combined. With only the one,
+    /// it's that dir's revision as it is.
+    ///
+    fn revision( &self ) -> Option<String> {
+        let mut revisions : Vec<(&Path, String)> = vec!();
+        for dir in self.dirs.iter().filter(|d| d.is_dir()) {
+            revisions.push((dir, dir_revision(dir)?));
+        }
+        if revisions.len() == 1 {
+            return revisions.pop().map(|(_, r)| r);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for &(dir, ref r) in revisions.iter() {
+            hasher.write(dir.to_string_lossy().as_bytes());
+            hasher.write(r.as_bytes());
+        }
+        Some(format!("{:016x}", hasher.finish()))
+    }
+
+    fn group<'a>( &self, group : &'a str ) -> Vec<Metadata> {
+        self.list().into_iter().filter(|m| m.groups.iter().any(|g| g == group)).collect()
+    }
+
+    fn versions<'a>( &self, name : &'a str ) -> Vec<String> {
+        self.list().into_iter().filter(|m| m.name == name).map(|m| m.version).collect()
+    }
+
+    fn file_owners<'a>( &self, path : &'a str ) -> Vec<(String, String)> {
+        self.files.get_or_init(|| FileIndex::from_packages( &self.list() )).owners( path )
+    }
+}
+
+///
+/// Repositories can publish their revision in REVISION. Otherwise we hash
+/// the name, size and modification time of every package file in dir, which
+/// is a lot cheaper than parsing them.
+///
+fn dir_revision( dir : &Path ) -> Option<String> {
+    let mut published = String::new();
+    match File::open(dir.join("REVISION")) {
+        Ok(mut f) => {
+            if f.read_to_string(&mut published).is_ok() && published.trim().len() > 0 {
+                return Some(published.trim().to_string());
+            }
+        },
+        Err(_) => {},
+    }
+
+    let mut files : Vec<(String, u64, u64)> = vec!();
+    for entry in read_dir(dir).ok()? {
+        let entry = entry.ok()?;
+        let meta = entry.metadata().ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        files.push((entry.file_name().to_string_lossy().to_string(),
+                    meta.len(),
+                    mtime.as_secs() * 1000000000 + mtime.subsec_nanos() as u64));
+    }
+    files.sort();
+
+    let mut hasher = DefaultHasher::new();
+    for &(ref name, size, mtime) in files.iter() {
+        hasher.write(name.as_bytes());
+        hasher.write_u64(size);
+        hasher.write_u64(mtime);
+    }
+    Some(format!("{:016x}", hasher.finish()))
+}
+
+fn read_toml<'a>( filename : &'a str ) -> Result<Metadata, ResolverError> {
+    let mut data = String::new();
+    let mut f = File::open(filename).map_err(|_| ResolverError::NoFile)?;
+    f.read_to_string(&mut data).map_err(|_| ResolverError::NoFile)?;
+
+    // Unpack TOML data
+    let value = toml::Parser::new(data.as_str()).parse().ok_or(ResolverError::BadSyntax)?;
+
+    // Extract data
+    // Header
+    let meta = value.get("metadata").and_then(|m| m.as_table()).ok_or(ResolverError::BadSyntax)?;
+
+    let version = toml_str( meta, "version" ).ok_or(ResolverError::BadSyntax)?;
+    let name = normalize( &toml_str( meta, "name" ).ok_or(ResolverError::BadSyntax)? ).map_err(ResolverError::BadName)?;
+
+    // Read dependencies. Leaf packages can leave the table out
+    let empty = toml::Table::new();
+    let deps = match value.get("depends") {
+        Some(d) => d.as_table().ok_or(ResolverError::BadSyntax)?,
+        None => &empty,
+    };
+    let mut dep_vector : Vec<Dependency> = vec!();
+    for (_, val) in deps.iter() {
+        let contents = val.as_table().ok_or(ResolverError::BadSyntax)?;
+
+        // Anything without a kind is needed at runtime
+        let kind = match contents.get("kind").and_then(|k| k.as_str()) {
+            Some(k) => DependencyKind::from_str(k).ok_or(ResolverError::BadSyntax)?,
+            None => DependencyKind::Runtime,
+        };
+
+        // Whoever ships a file satisfies a dep on it, whatever the version,
+        // so file deps can leave the range out
+        let dep_name = normalize_dep( &toml_str( contents, "name" ).ok_or(ResolverError::BadSyntax)? ).map_err(ResolverError::BadName)?;
+        let (min_version, max_version) = if is_file_dep( &dep_name ) {
+            (toml_str( contents, "minversion" ).unwrap_or("0".to_string()),
+             toml_str( contents, "maxversion" ).unwrap_or("0".to_string()))
+        } else {
+            (toml_str( contents, "minversion" ).ok_or(ResolverError::BadSyntax)?,
+             toml_str( contents, "maxversion" ).ok_or(ResolverError::BadSyntax)?)
+        };
+
+        let d = Dependency{
+            name : dep_name,
+            min_version : min_version,
+            max_version : max_version,
+            kind : kind,
+            slot : match contents.get("slot").and_then(|s| s.as_str()) {
+                Some(s) => Some(normalize( s ).map_err(ResolverError::BadName)?),
+                None => None,
+            }
+        };
+        dep_vector.push(d);
+    }
+
+    // Descriptive fields
+    let mut m = Metadata::new( name, version, dep_vector );
+    m.description = toml_str( meta, "description" );
+    m.license = toml_str( meta, "license" );
+    m.homepage = toml_str( meta, "homepage" );
+    m.maintainer = toml_str( meta, "maintainer" );
+    m.arch = toml_str( meta, "arch" ).unwrap_or(ANY_ARCH.to_string());
+    m.slot = match toml_str( meta, "slot" ) {
+        Some(s) => Some(normalize( &s ).map_err(ResolverError::BadName)?),
+        None => None,
+    };
+    m.installed_size = toml_size( meta, "installed_size" );
+    m.download_size = toml_size( meta, "download_size" );
+    m.checksum = toml_str( meta, "checksum" );
+    m.build_date = toml_str( meta, "build_date" );
+    m.repository = toml_str( meta, "repository" );
+    match meta.get("groups").and_then(|g| g.as_slice()) {
+        Some(groups) => {
+            for g in groups.iter() {
+                match g.as_str() {
+                    // Groups are requested like packages, so they follow
+                    // the same grammar
+                    Some(s) => m.groups.push(normalize( s ).map_err(ResolverError::BadName)?),
+                    None => return Err(ResolverError::BadSyntax),
+                }
+            }
+        },
+        None => {},
+    }
+    match meta.get("obsoletes").and_then(|o| o.as_slice()) {
+        Some(obsoletes) => {
+            for o in obsoletes.iter() {
+                match o.as_str() {
+                    Some(s) => m.obsoletes.push(normalize( s ).map_err(ResolverError::BadName)?),
+                    None => return Err(ResolverError::BadSyntax),
+                }
+            }
+        },
+        None => {},
+    }
+
+    match meta.get("files").and_then(|f| f.as_slice()) {
+        Some(files) => {
+            for f in files.iter() {
+                match f.as_str().map(normalize_dep) {
+                    Some(Ok(ref p)) if is_file_dep( p ) => m.files.push(p.to_string()),
+                    _ => return Err(ResolverError::BadSyntax),
+                }
+            }
+        },
+        None => {},
+    }
+    m.meta_package = meta.get("meta_package").and_then(|v| v.as_bool()).unwrap_or(false);
+
+    // Return metadata
+    Ok(m)
+}
+
+///
+/// Write meta out the way read_toml expects to find it, e.g. to bring
+/// packages over from another repository format
+///
+pub fn to_toml( meta : &Metadata ) -> String {
+    let mut header = toml::Table::new();
+    let mut put = |key : &str, value : Option<toml::Value>| {
+        match value {
+            Some(v) => { header.insert(key.to_string(), v); },
+            None => {},
         }
+    };
+    let string = |s : &Option<String>| s.as_ref().map(|s| toml::Value::String(s.clone()));
+    let size = |s : Option<u64>| s.map(|s| toml::Value::Integer(s as i64));
+    let list = |l : &Vec<String>| if l.len() > 0 {
+        Some(toml::Value::Array(l.iter().map(|s| toml::Value::String(s.clone())).collect()))
+    } else {
+        None
+    };
+
+    put("name", Some(toml::Value::String(meta.name.clone())));
+    put("version", Some(toml::Value::String(meta.version.clone())));
+    put("description", string(&meta.description));
+    put("license", string(&meta.license));
+    put("homepage", string(&meta.homepage));
+    put("maintainer", string(&meta.maintainer));
+    put("arch", Some(toml::Value::String(meta.arch.clone())));
+    put("slot", string(&meta.slot));
+    put("installed_size", size(meta.installed_size));
+    put("download_size", size(meta.download_size));
+    put("checksum", string(&meta.checksum));
+    put("build_date", string(&meta.build_date));
+    put("repository", string(&meta.repository));
+    put("groups", list(&meta.groups));
+    put("obsoletes", list(&meta.obsoletes));
+    put("files", list(&meta.files));
+    if meta.meta_package {
+        put("meta_package", Some(toml::Value::Boolean(true)));
+    }
+
+    // The keys only have to be unique, but the dep's name reads best
+    let mut depends = toml::Table::new();
+    for d in meta.deps.iter() {
+        let mut dep = toml::Table::new();
+        dep.insert("name".to_string(), toml::Value::String(d.name.clone()));
+        dep.insert("minversion".to_string(), toml::Value::String(d.min_version.clone()));
+        dep.insert("maxversion".to_string(), toml::Value::String(d.max_version.clone()));
+        if d.kind != DependencyKind::Runtime {
+            dep.insert("kind".to_string(), toml::Value::String(d.kind.as_str().to_string()));
+        }
+        match d.slot {
+            Some(ref s) => { dep.insert("slot".to_string(), toml::Value::String(s.clone())); },
+            None => {},
+        }
+
+        let mut key = d.name.clone();
+        let mut n = 1;
+        while depends.contains_key(&key) {
+            n += 1;
+            key = format!("{}-{}", d.name, n);
+        }
+        depends.insert(key, toml::Value::Table(dep));
+    }
+
+    let mut root = toml::Table::new();
+    root.insert("metadata".to_string(), toml::Value::Table(header));
+    root.insert("depends".to_string(), toml::Value::Table(depends));
+    toml::Value::Table(root).to_string()
+}
+
+fn toml_str<'a>( table : &toml::Table, key : &'a str ) -> Option<String> {
+    match table.get(key) {
+        Some(&toml::Value::String(ref s)) => Some(s.to_string()),
+        // Dates can be written bare in TOML
+        Some(&toml::Value::Datetime(ref s)) => Some(s.to_string()),
+        _ => None,
+    }
+}
 
-        // Return metadata
-        Ok(Metadata{ name : name, version : version, deps : dep_vector })
+fn toml_size<'a>( table : &toml::Table, key : &'a str ) -> Option<u64> {
+    match table.get(key).and_then(|v| v.as_integer()) {
+        Some(i) if i >= 0 => Some(i as u64),
+        _ => None,
     }
 }
@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use solver::package_resolver::Metadata;
+use solver::version::Version;
+use solver::name::normalize;
+
+extern crate toml;
+
+///
+/// Site specific rules on which packages may be installed. Context asks every
+/// policy about a version before it accepts it. A vetoed version gives way to
+/// the newest older one the rules still allow and every policy accepts, and
+/// only if there's none does the solve fail, with the reason given here.
+///
+pub trait Policy {
+    /// Short name of the policy, to say who vetoed what
+    fn name( &self ) -> &str;
+
+    /// Ok if meta may be installed, otherwise Err with the reason why not
+    fn check( &self, meta : &Metadata ) -> Result<(), String>;
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    NoFile,
+    BadSyntax,
+}
+
+struct BlockedRange {
+    name        : String,
+    min_version : Option<Version>,
+    max_version : Option<Version>,
+    reason      : String,
+}
+
+///
+/// A policy read from TOML, blocking version ranges of packages and whole
+/// licenses:
+///
+///     [[block]]
+///     name = "openssl"
+///     min_version = "1.0.1"     # both bounds are optional and inclusive
+///     max_version = "1.0.1.f"   # tokens are all digits or all letters
+///     reason = "heartbleed"
+///
+///     [licenses]
+///     forbid = ["AGPL-3.0"]
+///
+pub struct Blocklist {
+    blocked  : Vec<BlockedRange>,
+    licenses : Vec<String>,
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist { blocked : vec!(), licenses : vec!() }
+    }
+
+    pub fn load( path : &Path ) -> Result<Blocklist, PolicyError> {
+        let mut data = String::new();
+        let mut f = File::open( path ).map_err(|_| PolicyError::NoFile)?;
+        f.read_to_string( &mut data ).map_err(|_| PolicyError::NoFile)?;
+
+        let value = toml::Parser::new( data.as_str() ).parse().ok_or(PolicyError::BadSyntax)?;
+        let mut ret = Blocklist::new();
+
+        match value.get("block") {
+            Some(b) => {
+                for entry in b.as_slice().ok_or(PolicyError::BadSyntax)?.iter() {
+                    let name = entry.lookup("name").and_then(|n| n.as_str()).ok_or(PolicyError::BadSyntax)?;
+                    // A bound that can't be compared with would only turn
+                    // up later, in the middle of a solve
+                    let version = |key| -> Result<Option<Version>, PolicyError> {
+                        match entry.lookup(key) {
+                            Some(v) => {
+                                let v = Version::new( v.as_str().ok_or(PolicyError::BadSyntax)? );
+                                if v.is_valid() { Ok(Some(v)) } else { Err(PolicyError::BadSyntax) }
+                            },
+                            None => Ok(None),
+                        }
+                    };
+                    ret.blocked.push(BlockedRange {
+                        name        : normalize( name ).map_err(|_| PolicyError::BadSyntax)?,
+                        min_version : version("min_version")?,
+                        max_version : version("max_version")?,
+                        reason      : entry.lookup("reason").and_then(|r| r.as_str())
+                                           .unwrap_or("blocked by policy").to_string(),
+                    });
+                }
+            },
+            None => {},
+        }
+
+        match value.get("licenses").and_then(|l| l.lookup("forbid")) {
+            Some(forbid) => {
+                for l in forbid.as_slice().ok_or(PolicyError::BadSyntax)?.iter() {
+                    ret.licenses.push( l.as_str().ok_or(PolicyError::BadSyntax)?.to_string() );
+                }
+            },
+            None => {},
+        }
+
+        Ok(ret)
+    }
+}
+
+impl Policy for Blocklist {
+    fn name( &self ) -> &str {
+        "blocklist"
+    }
+
+    fn check( &self, meta : &Metadata ) -> Result<(), String> {
+        let version = Version::new( &meta.version );
+        for b in self.blocked.iter().filter(|b| b.name == meta.name) {
+            // A version that can't be placed against a bound, like 1.0.1f
+            // against 1.0.1.f, is taken to be inside it rather than risk
+            // letting through what the entry is there to stop
+            let above_min = match b.min_version {
+                Some(ref min) => version.try_cmp( min ).map(|c| c >= 0).unwrap_or(true),
+                None => true,
+            };
+            let below_max = match b.max_version {
+                Some(ref max) => version.try_cmp( max ).map(|c| c <= 0).unwrap_or(true),
+                None => true,
+            };
+            if above_min && below_max {
+                return Err(b.reason.clone());
+            }
+        }
+
+        match meta.license {
+            Some(ref l) if self.licenses.iter().any(|f| f.eq_ignore_ascii_case(l)) => {
+                Err(format!("license {} is forbidden", l))
+            },
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::thread;
+
+use solver::package_resolver::Metadata;
+use solver::package_resolver::Resolver;
+use solver::package_resolver::ResolverError;
+
+/// How many resolves a single prefetch may have running at once
+const PREFETCH_THREADS : usize = 8;
+
+enum Slot {
+    // Somebody is resolving this right now
+    Pending,
+    Ready(Metadata),
+}
+
+struct CacheState {
+    entries : Mutex<HashMap<(String, String), Slot>>,
+    changed : Condvar,
+}
+
+///
+/// Resolved metadata, keyed on (name, version), for one repository. Cloning
+/// the cache hands out another reference to the same entries.
+///
+#[derive(Clone)]
+pub struct MetadataCache {
+    state : Arc<CacheState>,
+}
+
+impl MetadataCache {
+    pub fn new() -> MetadataCache {
+        MetadataCache {
+            state : Arc::new(CacheState {
+                entries : Mutex::new(HashMap::new()),
+                changed : Condvar::new(),
+            }),
+        }
+    }
+
+    /// The cache shared by every solve in this process over the repository
+    /// at revision
+    pub fn global<'a>( revision : &'a str ) -> MetadataCache {
+        static GLOBAL : OnceLock<Mutex<HashMap<String, MetadataCache>>> = OnceLock::new();
+        GLOBAL.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap()
+            .entry( revision.to_string() )
+            .or_insert_with( MetadataCache::new )
+            .clone()
+    }
+
+    ///
+    /// Claim (name, version) for resolving. Returns the metadata if it's
+    /// already known, None if the caller now owns the resolve, and blocks if
+    /// someone else is part way through it.
+    ///
+    fn claim( &self, key : &(String, String) ) -> Option<Metadata> {
+        let mut entries = self.state.entries.lock().unwrap();
+        loop {
+            match entries.get(key) {
+                Some(&Slot::Ready(ref m)) => return Some(m.clone()),
+                Some(&Slot::Pending) => {},
+                None => {
+                    entries.insert(key.clone(), Slot::Pending);
+                    return None;
+                }
+            }
+            entries = self.state.changed.wait(entries).unwrap();
+        }
+    }
+
+    /// Try to claim (name, version) without blocking, for background work
+    fn try_claim( &self, key : &(String, String) ) -> bool {
+        let mut entries = self.state.entries.lock().unwrap();
+        if entries.contains_key(key) {
+            return false;
+        }
+        entries.insert(key.clone(), Slot::Pending);
+        return true;
+    }
+
+    /// Publish the outcome of a claimed resolve. Failures aren't cached, so
+    /// whoever asks next gets to see the error for themselves.
+    fn finish( &self, key : (String, String), result : &Result<Metadata, ResolverError> ) {
+        let mut entries = self.state.entries.lock().unwrap();
+        match *result {
+            Ok(ref m) => { entries.insert(key, Slot::Ready(m.clone())); },
+            Err(_) => { entries.remove(&key); },
+        }
+        self.state.changed.notify_all();
+    }
+}
+
+///
+/// Wraps another resolver, fetching prefetch hints on a handful of threads and
+/// remembering everything in a MetadataCache. The solve itself still happens
+/// in order, so the results are the same as with the inner resolver alone.
+///
+pub struct PrefetchResolver<R> {
+    inner : Arc<R>,
+    cache : MetadataCache,
+}
+
+impl<R : Resolver + Send + Sync + 'static> PrefetchResolver<R> {
+    ///
+    /// Prefetch through the process wide cache for inner's repository. One
+    /// that can't tell its revision gets a cache of its own, as there's no
+    /// telling which other resolvers are over the same packages.
+    ///
+    pub fn new( inner : R ) -> PrefetchResolver<R> {
+        let cache = match inner.revision() {
+            Some(rev) => MetadataCache::global( &rev ),
+            None => MetadataCache::new(),
+        };
+        PrefetchResolver::with_cache( inner, cache )
+    }
+
+    /// Prefetch through a specific cache, e.g. for a different repository
+    pub fn with_cache( inner : R, cache : MetadataCache ) -> PrefetchResolver<R> {
+        PrefetchResolver { inner : Arc::new(inner), cache : cache }
+    }
+}
+
+impl<R : Resolver + Send + Sync + 'static> Resolver for PrefetchResolver<R> {
+    fn resolve<'a>( &self, name : &'a str, version : &'a str ) -> Result<Metadata, ResolverError> {
+        let key = (name.to_string(), version.to_string());
+        match self.cache.claim(&key) {
+            Some(m) => Ok(m),
+            None => {
+                let result = self.inner.resolve( name, version );
+                self.cache.finish( key, &result );
+                result
+            }
+        }
+    }
+
+    fn revision( &self ) -> Option<String> {
+        self.inner.revision()
+    }
+
+    fn group<'a>( &self, group : &'a str ) -> Vec<Metadata> {
+        self.inner.group( group )
+    }
+
+    fn versions<'a>( &self, name : &'a str ) -> Vec<String> {
+        self.inner.versions( name )
+    }
+
+    fn file_owners<'a>( &self, path : &'a str ) -> Vec<(String, String)> {
+        self.inner.file_owners( path )
+    }
+
+    fn prefetch( &self, wanted : &[(String, String)] ) {
+        // Only queue up what nobody has fetched or started fetching yet
+        let queue : VecDeque<(String, String)> = wanted.iter()
+            .filter(|k| self.cache.try_claim(k))
+            .cloned()
+            .collect();
+        if queue.len() == 0 {
+            return;
+        }
+
+        let workers = if queue.len() < PREFETCH_THREADS { queue.len() } else { PREFETCH_THREADS };
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let inner = self.inner.clone();
+            let cache = self.cache.clone();
+
+            // Nobody joins these. Anyone who needs a result waits on the
+            // cache instead
+            thread::spawn(move || {
+                loop {
+                    let next = queue.lock().unwrap().pop_front();
+                    match next {
+                        Some(key) => {
+                            // If the inner resolver panics, give up on the
+                            // entry so the real resolve can panic in the
+                            // caller's thread instead of waiting forever
+                            let result = catch_unwind(AssertUnwindSafe(|| inner.resolve( &key.0, &key.1 )))
+                                .unwrap_or(Err(ResolverError::NoFile));
+                            cache.finish( key, &result );
+                        },
+                        None => break,
+                    }
+                }
+            });
+        }
+    }
+}
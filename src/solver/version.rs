@@ -64,6 +64,44 @@ impl Version {
 
         }
     }
+
+    ///
+    /// Whether every token is all digits or all letters, which is all cmp
+    /// can make sense of. 1.0.1f has to be written 1.0.1.f
+    ///
+    pub fn is_valid(&self) -> bool {
+        self.data.split(&['.', '-'][..]).all(|t| {
+            t.len() > 0 && (t.chars().all(|c| c.is_ascii_digit()) || t.chars().all(|c| c.is_ascii_alphabetic()))
+        })
+    }
+
+    ///
+    /// Like cmp, but None instead of a panic when a number has to be
+    /// compared with something that isn't one, whichever side it's on
+    ///
+    pub fn try_cmp(&self, other: &Version) -> Option<i32> {
+        let delimiters = ['.', '-'];
+        let mut self_tokens = self.data.split(&delimiters[..]);
+        let mut other_tokens = other.data.split(&delimiters[..]);
+
+        loop {
+            match (self_tokens.next(), other_tokens.next()) {
+                (Some(x), Some(y)) => {
+                    let res = match (x.parse::<u32>(), y.parse::<u32>()) {
+                        (Ok(a), Ok(b)) => if a > b { 1 } else if a < b { -1 } else { 0 },
+                        (Err(_), Err(_)) => if x > y { 1 } else if x < y { -1 } else { 0 },
+                        _ => return None,
+                    };
+                    if res != 0 {
+                        return Some(res);
+                    }
+                },
+                (Some(_), None) => return Some(1),
+                (None, Some(_)) => return Some(-1),
+                (None, None) => return Some(0),
+            }
+        }
+    }
 }
 
 
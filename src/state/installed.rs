@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::create_dir_all;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use solver::context::Reason;
+use solver::context::Replacement;
+use solver::version::Version;
+
+extern crate toml;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io,
+    BadSyntax,
+}
+
+/// Why a package is on the system
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstallReason {
+    /// Somebody asked for it by name
+    Explicit,
+    /// Only here because something else needs it
+    Dependency,
+}
+
+impl InstallReason {
+    pub fn from_str<'a>( s : &'a str ) -> Option<InstallReason> {
+        match s {
+            "explicit"   => Some(InstallReason::Explicit),
+            "dependency" => Some(InstallReason::Dependency),
+            _            => None,
+        }
+    }
+
+    pub fn as_str( &self ) -> &'static str {
+        match *self {
+            InstallReason::Explicit   => "explicit",
+            InstallReason::Dependency => "dependency",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InstalledPackage {
+    /// Node key of the package, i.e. name or name:slot
+    pub name    : String,
+    pub version : String,
+    pub reason  : InstallReason,
+}
+
+///
+/// The record of what is installed, kept as TOML:
+///
+///     [packages.vim]
+///     version = "7.4"
+///     reason = "explicit"
+///
+pub struct InstalledState {
+    pub packages : BTreeMap<String, InstalledPackage>,
+}
+
+impl InstalledState {
+    pub fn new() -> InstalledState {
+        InstalledState { packages : BTreeMap::new() }
+    }
+
+    /// Read the record at path. A missing record means nothing is installed
+    pub fn load( path : &Path ) -> Result<InstalledState, StateError> {
+        let mut ret = InstalledState::new();
+
+        let mut data = String::new();
+        match File::open( path ) {
+            Ok(mut f) => { f.read_to_string( &mut data ).map_err(|_| StateError::Io)?; },
+            Err(_) => return Ok(ret),
+        }
+
+        let value = toml::Parser::new( data.as_str() ).parse().ok_or(StateError::BadSyntax)?;
+        let packages = match value.get("packages") {
+            Some(p) => p.as_table().ok_or(StateError::BadSyntax)?,
+            None => return Ok(ret),
+        };
+
+        for (name, entry) in packages.iter() {
+            let version = entry.lookup("version").and_then(|v| v.as_str()).ok_or(StateError::BadSyntax)?;
+            let reason = entry.lookup("reason").and_then(|r| r.as_str())
+                              .and_then(InstallReason::from_str)
+                              .ok_or(StateError::BadSyntax)?;
+            ret.record( name, version, reason );
+        }
+
+        Ok(ret)
+    }
+
+    pub fn save( &self, path : &Path ) -> Result<(), StateError> {
+        let mut packages = toml::Table::new();
+        for (name, p) in self.packages.iter() {
+            let mut entry = toml::Table::new();
+            entry.insert("version".to_string(), toml::Value::String(p.version.clone()));
+            entry.insert("reason".to_string(), toml::Value::String(p.reason.as_str().to_string()));
+            packages.insert(name.clone(), toml::Value::Table(entry));
+        }
+        let mut root = toml::Table::new();
+        root.insert("packages".to_string(), toml::Value::Table(packages));
+
+        match path.parent() {
+            Some(dir) => create_dir_all( dir ).map_err(|_| StateError::Io)?,
+            None => {},
+        }
+        let mut f = File::create( path ).map_err(|_| StateError::Io)?;
+        f.write_all( toml::Value::Table(root).to_string().as_bytes() ).map_err(|_| StateError::Io)
+    }
+
+    /// Add or update a package
+    pub fn record<'a>( &mut self, name : &'a str, version : &'a str, reason : InstallReason ) {
+        self.packages.insert( name.to_string(), InstalledPackage {
+            name    : name.to_string(),
+            version : version.to_string(),
+            reason  : reason,
+        });
+    }
+}
+
+impl InstalledState {
+    ///
+    /// Fold the output of Context::flatten into the record. Packages keep the
+    /// reason they were installed with, and a package that replaced another
+    /// through obsoletes inherits the reason of the one it replaced. Anything
+    /// new is explicit if it was injected, and a dependency otherwise.
+    ///
+    /// With prune set, the solve is taken to cover the whole system (as in an
+    /// upgrade), so anything it doesn't mention is dropped from the record.
+    ///
+    pub fn record_solve( &mut self, solved : &[(String, Version, Vec<Reason>)],
+                         replacements : &[Replacement], prune : bool ) {
+        let mut next : BTreeMap<String, InstalledPackage> = if prune { BTreeMap::new() } else { self.packages.clone() };
+
+        for &(ref name, ref version, ref why) in solved.iter() {
+            let requested = why.iter().any(|r| match *r { Reason::Explicit => true, _ => false });
+            let replaced = replacements.iter().find(|r| r.new == *name)
+                                       .and_then(|r| self.packages.get(&r.old));
+
+            let reason = match (replaced, self.packages.get(name)) {
+                (Some(old), _) => old.reason,
+                (None, Some(p)) if p.reason == InstallReason::Explicit => InstallReason::Explicit,
+                _ if requested => InstallReason::Explicit,
+                _ => InstallReason::Dependency,
+            };
+
+            next.insert( name.clone(), InstalledPackage {
+                name    : name.clone(),
+                version : version.data.clone(),
+                reason  : reason,
+            });
+        }
+
+        for r in replacements.iter() {
+            next.remove( &r.old );
+        }
+
+        self.packages = next;
+    }
+}